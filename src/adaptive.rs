@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::config::AdaptiveConfig;
+use crate::util::{average_vec_deque, std_dev_vec_deque};
+
+// Rolling z-score of the primary detector's score. The z-score needed to fire rises by `sigma_inc` after
+// every stimulation so the animal habituating to the event doesn't keep triggering it, and decays back to
+// `base_sigma` exponentially with `decay_secs` as the time constant
+pub struct AdaptiveThreshold {
+    config: AdaptiveConfig,
+    history: VecDeque<f64>,
+    // Window end time and z-score of the last score pushed. The analysis thread scores the newest window
+    // until another sample arrives, repeats get that z-score back without joining the history again
+    last: Option<(f64, Option<f64>)>,
+    sigma: f64,
+    updated: Instant,
+}
+
+impl AdaptiveThreshold {
+    pub fn new(config: AdaptiveConfig) -> Self {
+        Self {
+            history: VecDeque::with_capacity(config.history + 1),
+            last: None,
+            sigma: config.base_sigma,
+            updated: Instant::now(),
+            config,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // z-score of `score`, for the window ending at `time`, against the scores before it, then `score` joins
+    // the history. None until there are `min_history` scores or while they are all equal. Only windows ending
+    // after the last one pushed count
+    pub fn push(&mut self, time: f64, score: f64) -> Option<f64> {
+        if let Some((last, zscore)) = self.last {
+            if time <= last {
+                return zscore;
+            }
+        }
+        let zscore = self.stats().map(|(average, stddev)| (score - average) / stddev);
+        self.last = Some((time, zscore));
+        self.history.push_back(score);
+        if self.history.len() > self.config.history {
+            self.history.pop_front();
+        }
+        zscore
+    }
+
+    // Mean and standard deviation of the history, once there is enough of it
+    fn stats(&self) -> Option<(f64, f64)> {
+        if self.history.len() < self.config.min_history {
+            return None;
+        }
+        let average = average_vec_deque(&self.history)?;
+        let stddev = std_dev_vec_deque(&self.history).filter(|s| *s > 0.0)?;
+        Some((average, stddev))
+    }
+
+    // Required z-score at `now`
+    pub fn sigma(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let base = self.config.base_sigma;
+        self.sigma = base + (self.sigma - base) * (-elapsed / self.config.decay_secs).exp();
+        self.updated = now;
+        self.sigma
+    }
+
+    pub fn on_stimulation(&mut self, now: Instant) {
+        self.sigma = (self.sigma(now) + self.config.sigma_inc).min(self.config.max_sigma);
+    }
+
+    // The score the detector has to reach to fire right now, for the plot
+    pub fn score_threshold(&mut self, now: Instant) -> Option<f64> {
+        let sigma = self.sigma(now);
+        self.stats().map(|(average, stddev)| average + sigma * stddev)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sigma_rises_and_decays() {
+        let config = AdaptiveConfig { enabled: true, min_history: 2, base_sigma: 3.0, max_sigma: 3.15, sigma_inc: 0.1, decay_secs: 10.0, ..AdaptiveConfig::default() };
+        let mut adaptive = AdaptiveThreshold::new(config);
+        let start = Instant::now();
+
+        assert_eq!(adaptive.push(0.1, 1.0), None);
+        assert_eq!(adaptive.push(0.2, 3.0), None);
+        assert_eq!(adaptive.push(0.3, 5.0), Some(3.0));
+
+        adaptive.on_stimulation(start);
+        assert!((adaptive.sigma(start) - 3.1).abs() < 1e-9);
+        adaptive.on_stimulation(start);
+        assert!((adaptive.sigma(start) - 3.15).abs() < 1e-9);
+        let decayed = adaptive.sigma(start + Duration::from_secs(10));
+        assert!((decayed - (3.0 + 0.15 / std::f64::consts::E)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rescored_windows_join_the_history_once() {
+        let config = AdaptiveConfig { enabled: true, min_history: 2, history: 3, ..AdaptiveConfig::default() };
+        let mut adaptive = AdaptiveThreshold::new(config);
+        adaptive.push(0.1, 1.0);
+        adaptive.push(0.2, 3.0);
+        // The same window scored over and over until the next sample
+        for _ in 0..100 {
+            assert_eq!(adaptive.push(0.3, 5.0), Some(3.0));
+        }
+        assert_eq!(adaptive.history, VecDeque::from(vec![1.0, 3.0, 5.0]));
+        assert_eq!(adaptive.push(0.4, 3.0), Some(0.0));
+    }
+}
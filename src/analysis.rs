@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use csv::Writer;
+use tracing::{info, warn};
+
+use crate::channels::ChannelLayout;
+use crate::config::RasaConfig;
+use crate::detector::{Detector, Window};
+use crate::model::Model;
+use crate::processing::Processor;
+use crate::resample::WindowBuffer;
+
+// Walk a recording (data<N>.csv format) the way the live pipeline would see it: every row goes through the same
+// filters, detrending and ΔF/F as in the input stream, starting from the raw signal and isosbestic columns, and `f` is called with the
+// last window_secs resampled to window_len once per output step (window_secs / (window_len - 1) of data time).
+// Malformed rows are skipped with a warning, like a replay of the same file does. Returns the number of windows
+pub fn for_each_window<F>(file: &Path, config: &RasaConfig, mut f: F) -> Result<usize, Box<dyn Error>>
+where
+    F: FnMut(&Window) -> Result<(), Box<dyn Error>>,
+{
+    let (window_secs, window_len) = (config.acquisition.window_secs, config.acquisition.window_len);
+    let step = window_secs / (window_len - 1) as f64;
+    let layout = ChannelLayout::new(config);
+    let (signal, isosbestic) = (2 + layout.signal, 2 + layout.isosbestic);
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(File::open(file)?);
+    let mut processor = Processor::new(&config.processing);
+    let mut buffer = WindowBuffer::new(window_len, 0.0);
+    let mut next: Option<f64> = None;
+    let mut count = 0;
+
+    for (ix, result) in reader.records().enumerate() {
+        let row = ix + 1;
+        let record = match result {
+            Ok(record) => record,
+            Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => return Err(e.into()),
+            Err(e) => {
+                warn!("Skipping row {}: {}", row, e);
+                continue;
+            }
+        };
+        if record.len() <= signal.max(isosbestic) {
+            warn!("Skipping row {}: {} columns, expected at least {}", row, record.len(), signal.max(isosbestic) + 1);
+            continue;
+        }
+        let column = |ix: usize| record[ix].trim().parse::<f64>();
+        let (time, raw_signal, raw_isosbestic) = match (column(0), column(signal), column(isosbestic)) {
+            (Ok(time), _, _) if !time.is_finite() => {
+                warn!("Skipping row {}: time is {}", row, time);
+                continue;
+            }
+            (Ok(time), Ok(raw_signal), Ok(raw_isosbestic)) => (time, raw_signal, raw_isosbestic),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                warn!("Skipping row {}: {}", row, e);
+                continue;
+            }
+        };
+        let processed = processor.push(time, raw_signal, raw_isosbestic);
+        buffer.push(time, processed.y0, processed.y1, processed.dff, window_secs);
+        if next.map_or(false, |next| time < next) {
+            continue;
+        }
+        if let Some(window) = buffer.window(window_secs) {
+            f(&window.as_window())?;
+            count += 1;
+            next = Some(time + step);
+        }
+    }
+    Ok(count)
+}
+
+// Offline equivalent of the live analysis thread. The rewards are written in the same format as the live
+// reward<N>.csv. Returns the number of windows scored
+pub fn analyze_recording(detector: &mut dyn Detector, file: &Path, output: &Path, config: &RasaConfig) -> Result<usize, Box<dyn Error>> {
+    let mut r_writer: Writer<File> = Writer::from_path(output)?;
+
+    let scored = for_each_window(file, config, |window| {
+        let detection = detector.detect(window)?;
+        r_writer.write_record(&[
+            window.times[0].to_string(),
+            window.times[window.times.len() - 1].to_string(),
+            detection.score.to_string(),
+            detection.label,
+        ])?;
+        Ok(())
+    })?;
+    r_writer.flush()?;
+    info!("Scored {} windows from {:?} into {:?} with {}", scored, file, output, detector.name());
+    Ok(scored)
+}
+
+#[derive(Debug, Default)]
+pub struct BackendComparison {
+    pub windows: usize,
+    // Largest absolute difference between any two corresponding embedding values
+    pub max_abs_diff: f64,
+    pub mean_abs_diff: f64,
+}
+
+// Embed every window of a recording with both models and measure how far apart their outputs are.
+// Used to check an ONNX export against the TorchScript model it was exported from. A recording too short for a
+// single window is an error, not an agreement
+pub fn compare_backends(reference: &Model, candidate: &Model, file: &Path, config: &RasaConfig) -> Result<BackendComparison, Box<dyn Error>> {
+    let mut comparison = BackendComparison::default();
+    let mut total_diff = 0.0;
+    let mut values = 0usize;
+
+    comparison.windows = for_each_window(file, config, |window| {
+        let a = reference.embed(window.v0, window.v1)?;
+        let b = candidate.embed(window.v0, window.v1)?;
+        if a.len() != b.len() {
+            return Err(format!("Models disagree on output size: {} vs {}", a.len(), b.len()).into());
+        }
+        for (x, y) in a.iter().zip(&b) {
+            let diff = (x - y).abs();
+            comparison.max_abs_diff = comparison.max_abs_diff.max(diff);
+            total_diff += diff;
+        }
+        values += a.len();
+        Ok(())
+    })?;
+    if comparison.windows == 0 {
+        return Err(format!("{:?} is shorter than one {} s window, nothing was compared", file, config.acquisition.window_secs).into());
+    }
+
+    if values > 0 {
+        comparison.mean_abs_diff = total_diff / values as f64;
+    }
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Embedder, ModelError, ModelMetadata, Normalization};
+
+    // Sums the normalized window, scaled
+    struct Sum(f64);
+
+    impl Embedder for Sum {
+        fn forward(&self, input: &[f32]) -> Result<Vec<f64>, ModelError> {
+            Ok(vec![self.0 * input.iter().map(|x| *x as f64).sum::<f64>()])
+        }
+    }
+
+    fn model(scale: f64) -> Model {
+        let meta = ModelMetadata {
+            description: String::new(),
+            input_len: 8,
+            channels: vec![String::from("signal"), String::from("isosbestic")],
+            normalization: Normalization { stddev: 1.0, channel_offsets: vec![0.0, 3.0] },
+            output_dim: 1,
+            default_template: None,
+        };
+        Model::with_embedder(meta, Box::new(Sum(scale)))
+    }
+
+    fn recording(secs: f64) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("rasa_compare_{}_{}.csv", std::process::id(), secs));
+        let mut csv = String::from("time,unix_ms,signal,isosbestic,ttl\n");
+        for i in 0..(secs * 100.0) as usize {
+            let t = i as f64 / 100.0;
+            csv.push_str(&format!("{},0,{},{},1\n", t, (t * 3.0).sin(), (t * 0.5).cos()));
+        }
+        std::fs::write(&path, csv).unwrap();
+        path
+    }
+
+    #[test]
+    fn compares_backends_and_refuses_short_recordings() {
+        let mut config = RasaConfig::default();
+        config.acquisition.window_len = 8;
+        config.acquisition.window_secs = 1.0;
+
+        let (short, long) = (recording(0.5), recording(3.0));
+        assert!(compare_backends(&model(1.0), &model(1.0), &short, &config).is_err());
+        let same = compare_backends(&model(1.0), &model(1.0), &long, &config).unwrap();
+        assert!(same.windows > 10 && same.max_abs_diff == 0.0, "{:?}", same);
+        let off = compare_backends(&model(1.0), &model(1.001), &long, &config).unwrap();
+        assert!(off.max_abs_diff > 0.0 && off.mean_abs_diff <= off.max_abs_diff, "{:?}", off);
+        std::fs::remove_file(short).ok();
+        std::fs::remove_file(long).ok();
+    }
+
+    #[test]
+    fn skips_malformed_rows() {
+        let mut config = RasaConfig::default();
+        config.acquisition.window_len = 8;
+        config.acquisition.window_secs = 1.0;
+
+        let clean = recording(2.5);
+        let broken = std::env::temp_dir().join(format!("rasa_broken_{}.csv", std::process::id()));
+        let text = std::fs::read_to_string(&clean).unwrap();
+        let with_bad_rows = text.replace("\n1,0,", "\noops\nnan,0,1,1,1\n1.005,0,x,1,1\n1,0,");
+        assert_ne!(text, with_bad_rows);
+        std::fs::write(&broken, with_bad_rows).unwrap();
+        let count = |file: &Path| for_each_window(file, &config, |_| Ok(())).unwrap();
+        assert_eq!(count(&broken), count(&clean));
+        std::fs::remove_file(clean).ok();
+        std::fs::remove_file(broken).ok();
+    }
+}
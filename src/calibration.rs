@@ -0,0 +1,398 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::*;
+use egui::plot::{Bar, BarChart, Plot, VLine};
+use tracing::{error, info, warn};
+
+use crate::config::{CalibrationConfig, CalibrationTarget, RasaConfig};
+use crate::events::{EventKind, EventLog};
+use crate::model::SharedModel;
+use crate::structs::RasaVariables;
+use crate::util::unix_timestamp_ms;
+
+// Thresholds tried when aiming for an event rate. Counting events is linear in the baseline, so the
+// candidates are quantiles of the scores rather than every distinct score
+const RATE_CANDIDATES: usize = 256;
+
+// Detector scores recorded over a baseline period. The analysis thread feeds it every new window while
+// recording and holds off stimulation until the baseline is complete. Times are data times so a replayed
+// recording calibrates the same as a live one
+pub struct Calibration {
+    recording_until: Option<f64>,
+    scores: Vec<(f64, f64)>,
+}
+
+impl Calibration {
+    pub fn new() -> Self {
+        Self { recording_until: None, scores: Vec::new() }
+    }
+
+    pub fn start(&mut self, now: f64, baseline_secs: f64) {
+        self.scores.clear();
+        self.recording_until = Some(now + baseline_secs);
+    }
+
+    pub fn cancel(&mut self) {
+        self.recording_until = None;
+        self.scores.clear();
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording_until.is_some()
+    }
+
+    // Add the score of the window ending at `time`. The analysis loop sees the same window more than once,
+    // only windows newer than the last recorded one count. Returns whether the baseline is still recording
+    pub fn record(&mut self, time: f64, score: f64) -> bool {
+        let until = match self.recording_until {
+            Some(until) => until,
+            None => return false,
+        };
+        if time >= until {
+            self.recording_until = None;
+            return false;
+        }
+        if self.scores.last().map_or(true, |(last, _)| time > *last) && score.is_finite() {
+            self.scores.push((time, score));
+        }
+        true
+    }
+
+    pub fn scores(&self) -> &[(f64, f64)] {
+        &self.scores
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        match (self.scores.first(), self.scores.last()) {
+            (Some((first, _)), Some((last, _))) => last - first,
+            _ => 0.0,
+        }
+    }
+
+    pub fn propose(&self, target: CalibrationTarget, cooldown_secs: f64) -> Option<f64> {
+        match target {
+            CalibrationTarget::FalsePositiveRate { rate } => threshold_for_fpr(&self.scores, rate),
+            CalibrationTarget::EventsPerMinute { rate } => threshold_for_rate(&self.scores, rate, cooldown_secs),
+        }
+    }
+}
+
+// Lowest baseline score with no more than `fpr` of the windows above it
+pub fn threshold_for_fpr(scores: &[(f64, f64)], fpr: f64) -> Option<f64> {
+    let mut sorted: Vec<f64> = scores.iter().map(|(_, s)| *s).collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = sorted.len();
+    let ix = ((1.0 - fpr) * n as f64).ceil() as usize;
+    Some(sorted[ix.clamp(1, n) - 1])
+}
+
+// Detections the baseline would have produced at `threshold`, one per cooldown like the analysis thread
+pub fn count_events(scores: &[(f64, f64)], threshold: f64, cooldown_secs: f64) -> usize {
+    let mut count = 0;
+    let mut next_allowed = f64::NEG_INFINITY;
+    for (time, score) in scores {
+        if *score > threshold && *time >= next_allowed {
+            count += 1;
+            next_allowed = time + cooldown_secs;
+        }
+    }
+    count
+}
+
+// Lowest candidate threshold giving no more than `per_minute` detections per minute of baseline
+pub fn threshold_for_rate(scores: &[(f64, f64)], per_minute: f64, cooldown_secs: f64) -> Option<f64> {
+    let minutes = match (scores.first(), scores.last()) {
+        (Some((first, _)), Some((last, _))) if last > first => (last - first) / 60.0,
+        _ => return None,
+    };
+    let mut sorted: Vec<f64> = scores.iter().map(|(_, s)| *s).collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    sorted.dedup();
+    let step = (sorted.len() / RATE_CANDIDATES).max(1);
+    let mut candidates: Vec<f64> = sorted.iter().step_by(step).copied().collect();
+    candidates.push(*sorted.last().unwrap());
+
+    candidates.into_iter().find(|threshold| count_events(scores, *threshold, cooldown_secs) as f64 / minutes <= per_minute)
+}
+
+// (bin center, count) over the range of the scores
+pub fn histogram(scores: &[(f64, f64)], bins: usize) -> Vec<(f64, usize)> {
+    let min = scores.iter().map(|(_, s)| *s).fold(f64::INFINITY, f64::min);
+    let max = scores.iter().map(|(_, s)| *s).fold(f64::NEG_INFINITY, f64::max);
+    if scores.is_empty() || bins == 0 {
+        return Vec::new();
+    }
+    let width = if max > min { (max - min) / bins as f64 } else { 1.0 };
+    let mut counts = vec![0; bins];
+    for (_, score) in scores {
+        let bin = (((score - min) / width) as usize).min(bins - 1);
+        counts[bin] += 1;
+    }
+    counts.into_iter().enumerate().map(|(ix, count)| (min + (ix as f64 + 0.5) * width, count)).collect()
+}
+
+// Per-subject settings kept between sessions in <profile_dir>/<subject>.toml. The threshold only applies to
+// the detector (and model) it was calibrated with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubjectProfile {
+    pub detector: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<PathBuf>,
+    pub threshold: f64,
+    pub calibrated_unix_ms: u64,
+    pub baseline_secs: f64,
+    pub windows: usize,
+    pub target: CalibrationTarget,
+}
+
+#[derive(Debug)]
+pub enum ProfileError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::Io(path, e) => write!(f, "could not access subject profile {:?}: {}", path, e),
+            ProfileError::Parse(path, e) => write!(f, "could not parse subject profile {:?}: {}", path, e),
+            ProfileError::Serialize(e) => write!(f, "could not write subject profile: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl SubjectProfile {
+    // None when the session has no subject
+    pub fn path_for(config: &RasaConfig) -> Option<PathBuf> {
+        let subject = config.session.subject.trim();
+        if subject.is_empty() {
+            None
+        } else {
+            Some(config.session.profile_dir.join(format!("{}.toml", subject)))
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ProfileError> {
+        let text = fs::read_to_string(path).map_err(|e| ProfileError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ProfileError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ProfileError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| ProfileError::Io(dir.to_path_buf(), e))?;
+        }
+        let text = toml::to_string_pretty(self).map_err(ProfileError::Serialize)?;
+        fs::write(path, text).map_err(|e| ProfileError::Io(path.to_path_buf(), e))
+    }
+
+    // Use the calibrated threshold if it was calibrated for the configured detector and model
+    pub fn apply(&self, config: &mut RasaConfig) {
+        let detector = config.detection.detector.kind();
+        let model = config.detection.detector.uses_model().then(|| config.model.path.clone());
+        if self.detector != detector || self.model != model {
+            warn!("Subject profile threshold was calibrated for the {} detector ({:?}), not {} ({:?}). Ignoring it",
+                self.detector, self.model, detector, model);
+            return;
+        }
+        info!("Using the calibrated threshold {} from the subject profile", self.threshold);
+        config.detection.detector.set_threshold(self.threshold);
+    }
+}
+
+// Start a baseline, look at the score histogram and accept the proposed threshold. Accepting sets the live
+// threshold and, when the session has a subject, saves it to the subject's profile
+pub struct CalibrationPanel {
+    calibration: Arc<Mutex<Calibration>>,
+    vars: Arc<RwLock<RasaVariables>>,
+    model: SharedModel,
+    events: EventLog,
+    config: CalibrationConfig,
+    detector: String,
+    uses_model: bool,
+    profile: Option<PathBuf>,
+
+    was_recording: bool,
+    status: String,
+    // Threshold accepted since the sidebar last asked, which it shouldn't log as an operator change again
+    accepted: Option<f64>,
+}
+
+impl CalibrationPanel {
+    pub fn new(calibration: Arc<Mutex<Calibration>>, vars: Arc<RwLock<RasaVariables>>, model: SharedModel, events: EventLog, config: &RasaConfig) -> Self {
+        Self {
+            calibration,
+            vars,
+            model,
+            events,
+            config: config.calibration.clone(),
+            detector: String::from(config.detection.detector.kind()),
+            uses_model: config.detection.detector.uses_model(),
+            profile: SubjectProfile::path_for(config),
+            was_recording: false,
+            status: String::new(),
+            accepted: None,
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.label("Calibration");
+        let calibration = Arc::clone(&self.calibration);
+        let mut calibration = calibration.lock().unwrap();
+        let recording = calibration.is_recording();
+        if self.was_recording && !recording {
+            info!("Calibration baseline finished with {} windows", calibration.scores().len());
+            self.events.log(EventKind::ParameterChange, format!("calibration baseline finished: {} windows", calibration.scores().len()));
+        }
+        self.was_recording = recording;
+
+        if recording {
+            ui.colored_label(egui::Color32::YELLOW, format!("Recording baseline, no stimulation: {:.0} / {:.0} s",
+                calibration.duration_secs(), self.config.baseline_secs));
+            ui.label(format!("{} windows", calibration.scores().len()));
+            if ui.button("Cancel").clicked() {
+                calibration.cancel();
+                self.was_recording = false;
+                info!("Calibration cancelled");
+                self.events.log(EventKind::ParameterChange, "calibration cancelled");
+            }
+            return;
+        }
+
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut self.config.baseline_secs).clamp_range(1.0..=3600.0).suffix(" s"));
+            if ui.button("Start baseline").clicked() {
+                calibration.start(self.events.time(), self.config.baseline_secs);
+                self.was_recording = true;
+                self.status.clear();
+                info!("Recording a {} s calibration baseline", self.config.baseline_secs);
+                self.events.log(EventKind::ParameterChange, format!("calibration started: {} s baseline", self.config.baseline_secs));
+            }
+        });
+
+        if calibration.scores().is_empty() {
+            if !self.status.is_empty() {
+                ui.label(&self.status);
+            }
+            return;
+        }
+
+        ui.horizontal(|ui| {
+            let is_fpr = matches!(self.config.target, CalibrationTarget::FalsePositiveRate { .. });
+            egui::ComboBox::from_id_source("calibration_target")
+                .selected_text(if is_fpr { "False positives" } else { "Events/min" })
+                .show_ui(ui, |ui| {
+                    if ui.selectable_label(is_fpr, "False positives").clicked() && !is_fpr {
+                        self.config.target = CalibrationTarget::FalsePositiveRate { rate: 0.01 };
+                    }
+                    if ui.selectable_label(!is_fpr, "Events/min").clicked() && is_fpr {
+                        self.config.target = CalibrationTarget::EventsPerMinute { rate: 1.0 };
+                    }
+                });
+            match &mut self.config.target {
+                CalibrationTarget::FalsePositiveRate { rate } => {
+                    ui.add(egui::DragValue::new(rate).speed(0.001).clamp_range(0.0001..=0.5));
+                }
+                CalibrationTarget::EventsPerMinute { rate } => {
+                    ui.add(egui::DragValue::new(rate).speed(0.05).clamp_range(0.01..=60.0));
+                }
+            }
+        });
+
+        let (threshold, cooldown_secs) = {
+            let vars = self.vars.read().unwrap();
+            (vars.threshold, vars.cooldown_secs as f64)
+        };
+        let proposed = calibration.propose(self.config.target, cooldown_secs);
+        let scores = calibration.scores();
+
+        let bars: Vec<Bar> = histogram(scores, self.config.bins).into_iter()
+            .map(|(center, count)| Bar::new(center, count as f64))
+            .collect();
+        let width = if bars.len() > 1 { bars[1].argument - bars[0].argument } else { 1.0 };
+        Plot::new("calibration_histogram")
+            .height(120.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .show(ui, |plot_ui| {
+                plot_ui.bar_chart(BarChart::new(bars.into_iter().map(|b| b.width(width)).collect()).color(egui::Color32::GOLD));
+                plot_ui.vline(VLine::new(threshold).color(egui::Color32::LIGHT_RED).name("threshold"));
+                if let Some(proposed) = proposed {
+                    plot_ui.vline(VLine::new(proposed).color(egui::Color32::LIGHT_GREEN).name("proposed"));
+                }
+            });
+
+        match proposed {
+            Some(proposed) => {
+                let above = scores.iter().filter(|(_, s)| *s > proposed).count() as f64 / scores.len() as f64;
+                let per_minute = count_events(scores, proposed, cooldown_secs) as f64 / (calibration.duration_secs() / 60.0).max(f64::EPSILON);
+                ui.label(format!("Proposed: {:.4}", proposed));
+                ui.label(format!("{:.2}% of windows, {:.2} events/min", above * 100.0, per_minute));
+                if ui.button("Accept").clicked() {
+                    self.status = self.accept(proposed, &calibration);
+                    self.accepted = Some(proposed);
+                }
+            }
+            None => { ui.label("Not enough baseline to propose a threshold"); },
+        }
+        if !self.status.is_empty() {
+            ui.label(&self.status);
+        }
+    }
+
+    pub fn take_accepted(&mut self) -> Option<f64> {
+        self.accepted.take()
+    }
+
+    fn accept(&self, threshold: f64, calibration: &Calibration) -> String {
+        let old = std::mem::replace(&mut self.vars.write().unwrap().threshold, threshold);
+        info!("Accepted calibrated threshold {} (was {})", threshold, old);
+        self.events.log(EventKind::ParameterChange, format!("threshold calibrated: {} -> {}", old, threshold));
+
+        let path = match &self.profile {
+            Some(path) => path,
+            None => return String::from("Threshold set. No session.subject, so no profile was saved"),
+        };
+        let profile = SubjectProfile {
+            detector: self.detector.clone(),
+            model: self.uses_model.then(|| self.model.read().unwrap().as_ref().map(|m| m.path.clone())).flatten(),
+            threshold,
+            calibrated_unix_ms: unix_timestamp_ms() as u64,
+            baseline_secs: calibration.duration_secs(),
+            windows: calibration.scores().len(),
+            target: self.config.target,
+        };
+        match profile.save(path) {
+            Ok(()) => { info!("Saved subject profile {:?}", path); format!("Saved to {}", path.display()) }
+            Err(e) => { error!("{}", e); e.to_string() }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn proposes_thresholds_for_targets() {
+        // One window a second, scores 0..99, a high score every 10 s
+        let scores: Vec<(f64, f64)> = (0..100).map(|i| (i as f64, if i % 10 == 0 { 50.0 + i as f64 } else { (i % 10) as f64 })).collect();
+        let fpr = threshold_for_fpr(&scores, 0.05).unwrap();
+        assert_eq!(scores.iter().filter(|(_, s)| *s > fpr).count(), 5);
+
+        // 10 events over 99 s is ~6/min, with a 20 s cooldown only 5 of them count
+        assert_eq!(count_events(&scores, 9.0, 20.0), 5);
+        let rate = threshold_for_rate(&scores, 2.0, 0.0).unwrap();
+        assert!(count_events(&scores, rate, 0.0) as f64 / (99.0 / 60.0) <= 2.0);
+        assert!(count_events(&scores, rate, 0.0) >= 3);
+    }
+}
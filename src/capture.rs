@@ -0,0 +1,161 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::*;
+use tracing::{error, info};
+
+use crate::events::{EventKind, EventLog};
+use crate::model::SharedModel;
+use crate::templates::Template;
+use crate::resample::WindowBuffer;
+use crate::structs::RasaVariables;
+
+// Turns a moment of the live plot into a detection template. Clicking or dragging on the measurement plot picks
+// where the event ends, and the model window ending there (the last window_secs, as set in the sidebar) is taken
+// from the analysis thread's buffer of processed samples, whatever the plot shows. It is resampled, normalized
+// and embedded exactly like the analysis thread's windows, then saved into the template directory and added to
+// the live template set
+pub struct TemplateCapture {
+    model: SharedModel,
+    templates: Arc<RwLock<Vec<Template>>>,
+    template_dir: PathBuf,
+    events: EventLog,
+    buffer: Arc<Mutex<WindowBuffer>>,
+    vars: Arc<RwLock<RasaVariables>>,
+
+    pub selection: Option<(f64, f64)>,
+    pub dragging: bool,
+    name: String,
+    status: String,
+    // A template file the operator has been asked about replacing
+    replace: Option<PathBuf>,
+}
+
+impl TemplateCapture {
+    pub fn new(model: SharedModel, templates: Arc<RwLock<Vec<Template>>>, template_dir: PathBuf, events: EventLog, buffer: Arc<Mutex<WindowBuffer>>, vars: Arc<RwLock<RasaVariables>>) -> Self {
+        Self {
+            model,
+            templates,
+            template_dir,
+            events,
+            buffer,
+            vars,
+            selection: None,
+            dragging: false,
+            name: String::new(),
+            status: String::new(),
+            replace: None,
+        }
+    }
+
+    // The window that would be captured, as (start, end). It ends where the selection does
+    pub fn span(&self) -> Option<(f64, f64)> {
+        let window_secs = self.vars.read().unwrap().window_secs;
+        self.selection.map(|(a, b)| (a.max(b) - window_secs, a.max(b)))
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.label("Template capture");
+        match self.span() {
+            Some((start, end)) => { ui.label(format!("Selected {:.2} s - {:.2} s", start, end)); },
+            None => { ui.label("Click the signal plot where an event ends"); },
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut self.name);
+        });
+
+        ui.horizontal(|ui| {
+            let can_save = self.span().is_some() && !self.name.trim().is_empty() && self.replace.is_none();
+            if ui.add_enabled(can_save, egui::Button::new("Save template")).clicked() {
+                self.save(false);
+            }
+            if ui.button("Clear").clicked() {
+                self.selection = None;
+                self.replace = None;
+            }
+        });
+
+        if let Some(path) = self.replace.clone() {
+            ui.colored_label(egui::Color32::YELLOW, format!("{} already exists", path.display()));
+            ui.horizontal(|ui| {
+                if ui.button("Replace it").clicked() {
+                    self.save(true);
+                }
+                if ui.button("Keep it").clicked() {
+                    self.replace = None;
+                }
+            });
+        } else if !self.status.is_empty() {
+            ui.label(&self.status);
+        }
+    }
+
+    fn save(&mut self, overwrite: bool) {
+        self.replace = None;
+        self.status = match self.capture(overwrite) {
+            Ok(template) => {
+                info!("Captured template '{}' from {:?}", template.name, self.span());
+                self.events.log(EventKind::ParameterChange, format!("template added: {}", template.name));
+                self.selection = None;
+                format!("Saved '{}'", template.name)
+            }
+            Err(CaptureError::Exists(path)) => {
+                self.replace = Some(path);
+                String::new()
+            }
+            Err(CaptureError::Failed(e)) => {
+                error!("Template capture failed: {}", e);
+                e.to_string()
+            }
+        };
+    }
+
+    // An existing file is only written over with `overwrite`, once the operator has confirmed it
+    fn capture(&self, overwrite: bool) -> Result<Template, CaptureError> {
+        let (start, end) = self.span().ok_or("Nothing selected")?;
+        let name = self.name.trim().to_string();
+        // The name becomes the file name, so it must not lead out of the template directory
+        if name.is_empty() || name.contains(['/', '\\', ':']) || name.contains("..") {
+            return Err("Template names can't be empty or contain '/', '\\', ':' or '..'".into());
+        }
+        if self.templates.read().unwrap().iter().any(|t| t.name == name) {
+            return Err(format!("A template named '{}' is already loaded", name).into());
+        }
+        let path = self.template_dir.join(format!("{}.json", name));
+        if path.exists() && !overwrite {
+            return Err(CaptureError::Exists(path));
+        }
+
+        let model = self.model.read().unwrap().clone().ok_or("No model loaded")?;
+        let window = self.buffer.lock().unwrap().window_ending(end, end - start)
+            .ok_or("The selection is no longer, or not yet, in the sample buffer")?;
+        let (v0, v1) = (window.v0, window.v1);
+        let template = Template {
+            name,
+            vector: model.embed(&v0, &v1)?,
+        };
+
+        // The model may have been swapped while this one was embedding, and its templates with it
+        let mut templates = self.templates.write().unwrap();
+        if !self.model.read().unwrap().as_ref().map_or(false, |m| Arc::ptr_eq(m, &model)) {
+            return Err("The model changed during capture, try again".into());
+        }
+        std::fs::create_dir_all(&self.template_dir)?;
+        template.save(&path)?;
+        templates.push(template.clone());
+        Ok(template)
+    }
+}
+
+enum CaptureError {
+    // The template file is already there, and replacing it needs the operator's say-so
+    Exists(PathBuf),
+    Failed(Box<dyn Error>),
+}
+
+impl<T: Into<Box<dyn Error>>> From<T> for CaptureError {
+    fn from(e: T) -> Self {
+        CaptureError::Failed(e.into())
+    }
+}
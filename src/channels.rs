@@ -0,0 +1,114 @@
+use crate::config::{ChannelConfig, ChannelRole, DetrendConfig, RasaConfig};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    pub unit: String,
+    pub role: ChannelRole,
+}
+
+impl Channel {
+    fn new(name: String, unit: String, role: ChannelRole) -> Self {
+        Self { name, unit, role }
+    }
+
+    // Column header in data<N>.csv
+    pub fn header(&self) -> String {
+        if self.unit.is_empty() { self.name.clone() } else { format!("{} ({})", self.name, self.unit) }
+    }
+}
+
+// Every channel of a session, in the order they are sent to the plots and recorded: the input source's columns
+// from [[channels]], then the derived ΔF/F and trends when those are enabled, then the detector's reward, which
+// only goes to the plots and reward<N>.csv. The first signal and isosbestic channels are the ones the filters,
+// detrending, ΔF/F and the model work on; further fibers are plotted and recorded as they come in
+#[derive(Debug, Clone)]
+pub struct ChannelLayout {
+    pub channels: Vec<Channel>,
+    pub inputs: usize,
+    pub signal: usize,
+    pub isosbestic: usize,
+    pub ttl: Option<usize>,
+    pub dff: Option<usize>,
+    pub reward: usize,
+}
+
+impl ChannelLayout {
+    // `config` must have passed validation, which guarantees a signal and an isosbestic channel
+    pub fn new(config: &RasaConfig) -> Self {
+        let inputs: &[ChannelConfig] = &config.channels;
+        let find = |role: ChannelRole| inputs.iter().position(|c| c.role == role);
+        let (signal, isosbestic) = (find(ChannelRole::Signal).unwrap_or(0), find(ChannelRole::Isosbestic).unwrap_or(1));
+        let mut channels: Vec<Channel> = inputs.iter().map(|c| Channel::new(c.name.clone(), c.unit.clone(), c.role)).collect();
+
+        let dff = config.processing.dff.then(|| push(&mut channels, String::from("ΔF/F"), String::new(), ChannelRole::Derived));
+        if config.processing.detrend != DetrendConfig::None {
+            for ix in [signal, isosbestic] {
+                let (name, unit) = (format!("{} trend", channels[ix].name), channels[ix].unit.clone());
+                push(&mut channels, name, unit, ChannelRole::Derived);
+            }
+        }
+        let reward = push(&mut channels, String::from("reward"), String::new(), ChannelRole::Reward);
+
+        Self { inputs: inputs.len(), signal, isosbestic, ttl: find(ChannelRole::Ttl), dff, reward, channels }
+    }
+
+    // Channels sent with every sample, i.e. all but the reward
+    pub fn sampled(&self) -> usize {
+        self.reward
+    }
+
+    // Channels drawn on the measurement plot
+    pub fn traces(&self) -> impl Iterator<Item = (usize, &Channel)> {
+        self.channels.iter().enumerate().filter(|(_, c)| matches!(c.role, ChannelRole::Signal | ChannelRole::Isosbestic))
+    }
+
+    // Header row of data<N>.csv
+    pub fn header(&self) -> Vec<String> {
+        let mut header = vec![String::from("time"), String::from("unix_ms")];
+        header.extend(self.channels[..self.sampled()].iter().map(Channel::header));
+        header
+    }
+}
+
+fn push(channels: &mut Vec<Channel>, name: String, unit: String, role: ChannelRole) -> usize {
+    channels.push(Channel::new(name, unit, role));
+    channels.len() - 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lays_out_two_fibers() {
+        let mut config: RasaConfig = toml::from_str(r#"
+            [processing]
+            dff = true
+            [[channels]]
+            name = "ttl"
+            role = "ttl"
+            [[channels]]
+            name = "gcamp left"
+            unit = "mV"
+            role = "signal"
+            [[channels]]
+            name = "iso left"
+            unit = "mV"
+            role = "isosbestic"
+            [[channels]]
+            name = "gcamp right"
+            role = "signal"
+        "#).unwrap();
+        let layout = ChannelLayout::new(&config);
+        assert_eq!((layout.inputs, layout.signal, layout.isosbestic, layout.ttl), (4, 1, 2, Some(0)));
+        assert_eq!((layout.dff, layout.reward), (Some(4), 5));
+        assert_eq!(layout.traces().map(|(ix, _)| ix).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(layout.header(), vec!["time", "unix_ms", "ttl", "gcamp left (mV)", "iso left (mV)", "gcamp right", "ΔF/F"]);
+
+        config.processing.detrend = DetrendConfig::HighPass { cutoff_hz: 0.01 };
+        let layout = ChannelLayout::new(&config);
+        assert_eq!(layout.channels[5], Channel::new(String::from("gcamp left trend"), String::from("mV"), ChannelRole::Derived));
+        assert_eq!(layout.reward, 7);
+    }
+}
@@ -0,0 +1,133 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::config::RasaConfig;
+use crate::inputstream::InputStreams;
+
+/// Closed-loop photometry acquisition and stimulation
+#[derive(Parser, Debug)]
+#[command(name = "rasa", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    #[command(flatten)]
+    pub session: SessionArgs,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Acquire from the photometry rig over serial
+    Run {
+        /// Serial port the photometry data arrives on
+        #[arg(long, default_value = "COM3")]
+        inport: String,
+        /// Serial port of the stimulator
+        #[arg(long, default_value = "COM4")]
+        outport: String,
+        /// Actually send stimulation commands to the outport
+        #[arg(long)]
+        stimulate: bool,
+    },
+    /// Play a previous recording back through the live pipeline
+    Replay {
+        /// Recording in the data<N>.csv format
+        file: PathBuf,
+    },
+    /// Run the pipeline on simulated data
+    Simulate {
+        #[arg(long, value_enum, default_value_t = Simulator::Ornstein)]
+        source: Simulator,
+    },
+    /// Score a recording offline with the model and write the rewards to a csv, without the GUI
+    Analyze {
+        /// Recording in the data<N>.csv format
+        file: PathBuf,
+        /// Where to write the rewards. Defaults to reward<N>.csv in the output directory
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check that two exports of a model agree, by embedding every window of a recording with both
+    CompareBackends {
+        /// Recording in the data<N>.csv format
+        file: PathBuf,
+        /// Model treated as ground truth, normally the TorchScript export
+        #[arg(long)]
+        reference: PathBuf,
+        /// Model being checked, normally the ONNX export
+        #[arg(long)]
+        candidate: PathBuf,
+        /// Largest acceptable absolute difference between embedding values
+        #[arg(long, default_value_t = 1e-4)]
+        tolerance: f64,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Simulator {
+    /// Ornstein-Uhlenbeck process with the TTL held high
+    Ornstein,
+    /// Sine wave with periodic spikes
+    Test,
+}
+
+// Options that apply to every subcommand. Anything given here overrides the session config
+#[derive(Args, Debug, Clone)]
+pub struct SessionArgs {
+    /// Session configuration file
+    #[arg(long, global = true, default_value = "rasa.toml")]
+    pub config: PathBuf,
+    /// Model used for detection (.pt TorchScript or .onnx)
+    #[arg(long, global = true)]
+    pub model: Option<PathBuf>,
+    /// Directory the data, reward and config files are written to
+    #[arg(long, global = true)]
+    pub output_dir: Option<PathBuf>,
+    /// Animal being recorded, selects its calibrated profile
+    #[arg(long, global = true)]
+    pub subject: Option<String>,
+    /// Experimental condition file, replaces the config's [condition]. Lets someone else set up a blinded session
+    #[arg(long, global = true)]
+    pub condition: Option<PathBuf>,
+    /// Score above which the detector triggers stimulation
+    #[arg(long, global = true)]
+    pub threshold: Option<f64>,
+    /// Minimum number of seconds between stimulations
+    #[arg(long, global = true)]
+    pub cooldown: Option<u64>,
+}
+
+impl SessionArgs {
+    pub fn apply(&self, config: &mut RasaConfig) {
+        if let Some(model) = &self.model {
+            config.model.path = model.clone();
+        }
+        if let Some(output_dir) = &self.output_dir {
+            config.session.output_dir = output_dir.clone();
+        }
+        if let Some(subject) = &self.subject {
+            config.session.subject = subject.clone();
+        }
+        if let Some(threshold) = self.threshold {
+            config.detection.detector.set_threshold(threshold);
+        }
+        if let Some(cooldown) = self.cooldown {
+            config.detection.cooldown_secs = cooldown;
+        }
+    }
+}
+
+impl Command {
+    // The live input source for this subcommand. The offline subcommands have none
+    pub fn input_stream(&self) -> Option<InputStreams> {
+        match self {
+            Command::Run { inport, outport, stimulate } =>
+                Some(InputStreams::PhotometryStream(inport.clone(), outport.clone(), *stimulate)),
+            Command::Replay { file } =>
+                Some(InputStreams::InstantReplayStream(file.to_string_lossy().into_owned())),
+            Command::Simulate { source: Simulator::Ornstein } => Some(InputStreams::OrnsteinStream),
+            Command::Simulate { source: Simulator::Test } => Some(InputStreams::TestStream),
+            Command::Analyze { .. } | Command::CompareBackends { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Write;
+use csv::Writer;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tracing::error;
+
+use crate::config::{ConditionConfig, ConditionProtocol};
+use crate::util::unix_timestamp_ms;
+
+// Runs the session's experimental condition in the analysis thread. Closed loop stimulates on the detector's
+// decisions, open loop on a schedule, sham records the decisions and suppresses them or replaces them with
+// stimulations at random times. Times are data times. What happens is written to the blinded condition<N>.csv
+// (data time, host unix time (ms), decision, detail), nothing in the GUI or the tracing log shows the protocol
+pub struct ConditionRunner<W: Write = File> {
+    protocol: ConditionProtocol,
+    rng: StdRng,
+    // Open loop: the next scheduled stimulation. Sham matched: the pending random ones, unordered
+    scheduled: Vec<f64>,
+    // Decisions that don't stimulate don't start the interlock's cooldown, so they are spaced out here instead
+    last_decision: Option<f64>,
+    log: Writer<W>,
+}
+
+// The cause every delivered detection or scheduled stimulation is logged with, whatever the protocol. The
+// detail only goes to condition<N>.csv
+pub const AUTOMATIC: &str = "automatic";
+
+impl<W: Write> ConditionRunner<W> {
+    // `condition` must have its seed set, see `resolve_seed`
+    pub fn new(condition: &ConditionConfig, log: Writer<W>) -> Self {
+        Self {
+            protocol: condition.protocol.clone(),
+            rng: StdRng::seed_from_u64(condition.seed.unwrap_or_default()),
+            scheduled: Vec::new(),
+            last_decision: None,
+            log,
+        }
+    }
+
+    // A decision that would stimulate in closed loop, made outside the interlock's cooldown. Returns whether to
+    // stimulate now. Other protocols ignore decisions within `cooldown_secs` of the last one they recorded, the
+    // way closed loop's cooldown would have
+    pub fn decide(&mut self, time: f64, cooldown_secs: f64, detail: &str) -> bool {
+        if self.protocol != ConditionProtocol::ClosedLoop && self.last_decision.map_or(false, |last| time - last < cooldown_secs) {
+            return false;
+        }
+        self.last_decision = Some(time);
+        match self.protocol {
+            ConditionProtocol::ClosedLoop => {
+                self.record(time, "would_stimulate", detail);
+                return true;
+            }
+            ConditionProtocol::ShamMatched { min_delay_secs, max_delay_secs } => {
+                let at = time + self.rng.gen_range(min_delay_secs..=max_delay_secs);
+                self.scheduled.push(at);
+                self.record(time, "would_stimulate", &format!("{}, sham at {:.3}", detail, at));
+            }
+            ConditionProtocol::OpenLoop { .. } | ConditionProtocol::ShamSuppress => self.record(time, "would_stimulate", detail),
+        }
+        false
+    }
+
+    // Whether a scheduled stimulation is due at `time`. The first open loop stimulation is one interval after
+    // the first call
+    pub fn due(&mut self, time: f64) -> bool {
+        match self.protocol {
+            ConditionProtocol::OpenLoop { interval_secs, jitter_secs } => {
+                let next = match self.scheduled.first() {
+                    Some(next) => *next,
+                    None => {
+                        let first = self.next_open_loop(time, interval_secs, jitter_secs);
+                        self.scheduled.push(first);
+                        first
+                    }
+                };
+                if time < next {
+                    return false;
+                }
+                // Scheduled from the planned time so the jitter doesn't accumulate
+                self.scheduled[0] = self.next_open_loop(next, interval_secs, jitter_secs).max(time);
+                self.record(time, "scheduled", &format!("planned for {:.3}", next));
+                true
+            }
+            ConditionProtocol::ShamMatched { .. } => match self.scheduled.iter().position(|at| *at <= time) {
+                Some(ix) => {
+                    let at = self.scheduled.swap_remove(ix);
+                    self.record(time, "scheduled", &format!("sham planned for {:.3}", at));
+                    true
+                }
+                None => false,
+            },
+            ConditionProtocol::ClosedLoop | ConditionProtocol::ShamSuppress => false,
+        }
+    }
+
+    fn next_open_loop(&mut self, from: f64, interval_secs: f64, jitter_secs: f64) -> f64 {
+        let jitter = if jitter_secs > 0.0 { self.rng.gen_range(-jitter_secs..=jitter_secs) } else { 0.0 };
+        from + interval_secs + jitter
+    }
+
+    // Outcome of a scheduled stimulation
+    pub fn delivered(&mut self, time: f64, delivered: bool, detail: &str) {
+        self.record(time, if delivered { "delivered" } else { "not_delivered" }, detail);
+    }
+
+    fn record(&mut self, time: f64, decision: &str, detail: &str) {
+        let result = self.log.write_record(&[time.to_string(), unix_timestamp_ms().to_string(), decision.to_string(), detail.to_string()])
+            .and_then(|_| self.log.flush().map_err(csv::Error::from));
+        if let Err(e) = result {
+            error!("Could not write to the condition log: {}", e);
+        }
+    }
+}
+
+// Draw a seed if the condition has none, so the sidecar is enough to reproduce the schedule
+pub fn resolve_seed(condition: &mut ConditionConfig) {
+    if condition.seed.is_none() {
+        condition.seed = Some(rand::random());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn runner(protocol: ConditionProtocol) -> ConditionRunner<Vec<u8>> {
+        ConditionRunner::new(&ConditionConfig { label: String::from("A"), seed: Some(7), protocol }, Writer::from_writer(Vec::new()))
+    }
+
+    #[test]
+    fn schedules_open_loop_and_sham() {
+        let mut open = runner(ConditionProtocol::OpenLoop { interval_secs: 10.0, jitter_secs: 2.0 });
+        assert!(!open.due(0.0));
+        let fired: Vec<f64> = (1..=400).map(|i| i as f64 * 0.1).filter(|t| open.due(*t)).collect();
+        assert!(fired.len() >= 3, "{:?}", fired);
+        assert!(fired.windows(2).all(|w| w[1] - w[0] > 7.8 && w[1] - w[0] < 12.2), "{:?}", fired);
+        assert!(!open.decide(5.0, 1.0, "detector"));
+
+        // Decisions within the cooldown of the last one don't schedule more shams
+        let mut sham = runner(ConditionProtocol::ShamMatched { min_delay_secs: 1.0, max_delay_secs: 3.0 });
+        assert!(!sham.decide(0.0, 5.0, "detector"));
+        assert!(!sham.decide(0.1, 5.0, "detector"));
+        assert!(!sham.due(0.9));
+        assert!((1..=30).any(|i| sham.due(i as f64 * 0.1)));
+        assert!(!sham.due(10.0));
+
+        assert!(runner(ConditionProtocol::ClosedLoop).decide(0.0, 5.0, "detector"));
+    }
+}
@@ -0,0 +1,1082 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Session configuration, normally read from rasa.toml. Every section and key is optional, anything left out
+// falls back to the defaults below, which are the values Rasa used to have compiled in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RasaConfig {
+    pub session: SessionConfig,
+    pub display: DisplayConfig,
+    pub acquisition: AcquisitionConfig,
+    pub processing: ProcessingConfig,
+    pub detection: DetectionConfig,
+    pub stimulation: StimulationConfig,
+    pub safety: SafetyConfig,
+    pub calibration: CalibrationConfig,
+    pub model: ModelConfig,
+    // The input source's columns, in order. See channels.rs for the derived channels added after them
+    pub channels: Vec<ChannelConfig>,
+    // Never written to the session's config copy so it can be opened without unblinding. The condition goes to
+    // the condition<N>.toml sidecar instead, see condition.rs
+    #[serde(skip_serializing)]
+    pub condition: ConditionConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    // Directory the data, reward and config copies are written to
+    pub output_dir: PathBuf,
+    // Animal being recorded. Its profile (<profile_dir>/<subject>.toml) holds the calibrated threshold
+    pub subject: String,
+    pub profile_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub show_box: bool,
+    // Seconds the graphs look backward
+    pub look_behind: usize,
+}
+
+// Longest model window. The processed samples behind it are kept in memory, and it has to fit on the plot
+pub const MAX_WINDOW_SECS: f64 = 25.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcquisitionConfig {
+    pub baud_rate: u32,
+    // Number of points the model sees at once, must match the model metadata input_len
+    pub window_len: usize,
+    // Seconds of data in one model window, resampled to `window_len` points whatever the sample rate
+    pub window_secs: f64,
+    // Replaced by window_secs and [[channels]], only read to point that out
+    #[serde(skip_serializing)]
+    pub skip: Option<usize>,
+    #[serde(skip_serializing)]
+    pub channels: Option<usize>,
+}
+
+// One column of the input source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub name: String,
+    #[serde(default)]
+    pub unit: String,
+    pub role: ChannelRole,
+}
+
+impl ChannelConfig {
+    pub fn new(name: &str, unit: &str, role: ChannelRole) -> Self {
+        Self { name: String::from(name), unit: String::from(unit), role }
+    }
+}
+
+// What a channel is. Inputs are signal, isosbestic or TTL; derived and reward channels are added by Rasa
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelRole {
+    Signal,
+    Isosbestic,
+    Ttl,
+    Derived,
+    Reward,
+}
+
+// Channels derived from the raw ones as samples arrive, see processing.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessingConfig {
+    // ΔF/F from the signal (channel 0) corrected with the isosbestic (channel 1). Plotted, recorded as an extra
+    // data<N>.csv column and available to detectors and rules as channel 2
+    pub dff: bool,
+    // Seconds of data the isosbestic is fitted to the signal over
+    pub fit_window_secs: f64,
+    // Nominal rate of the input stream, which the filters are designed for
+    pub sample_rate_hz: f64,
+    // Frequency the filters' group delay is reported at
+    pub delay_at_hz: f64,
+    // Slow drift removed from the signal and isosbestic channels (0 and 1 of the model window) before anything
+    // else sees them. The removed trend is recorded next to the raw values in data<N>.csv
+    pub detrend: DetrendConfig,
+    // Causal filters for model window channels 0 and 1, applied before detrending. One chain per channel, stages in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<FilterChainConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterChainConfig {
+    pub channel: usize,
+    pub stages: Vec<FilterStage>,
+}
+
+// One stage of a filter chain, see filters.rs. The pass filters are Butterworth
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum FilterStage {
+    LowPass {
+        cutoff_hz: f64,
+        #[serde(default = "default_filter_order")]
+        order: usize,
+    },
+    HighPass {
+        cutoff_hz: f64,
+        #[serde(default = "default_filter_order")]
+        order: usize,
+    },
+    // A high-pass at `low_hz` followed by a low-pass at `high_hz`
+    BandPass {
+        low_hz: f64,
+        high_hz: f64,
+        #[serde(default = "default_filter_order")]
+        order: usize,
+    },
+    Notch {
+        freq_hz: f64,
+        #[serde(default = "default_notch_q")]
+        q: f64,
+    },
+    // Windows are in samples
+    MovingAverage { window: usize },
+    Median { window: usize },
+    SavitzkyGolay {
+        window: usize,
+        #[serde(default = "default_poly_order")]
+        poly_order: usize,
+    },
+}
+
+fn default_filter_order() -> usize {
+    2
+}
+
+fn default_notch_q() -> f64 {
+    30.0
+}
+
+fn default_poly_order() -> usize {
+    2
+}
+
+impl FilterStage {
+    fn validate(&self, name: &str, sample_rate_hz: f64, problems: &mut Vec<String>) {
+        let nyquist = sample_rate_hz / 2.0;
+        let mut frequency = |field: &str, hz: f64| {
+            if !(hz > 0.0 && hz < nyquist) {
+                problems.push(format!("{}.{} must be between 0 and the Nyquist frequency ({} Hz), got {}", name, field, nyquist, hz));
+            }
+        };
+        match *self {
+            FilterStage::LowPass { cutoff_hz, .. } | FilterStage::HighPass { cutoff_hz, .. } => frequency("cutoff_hz", cutoff_hz),
+            FilterStage::BandPass { low_hz, high_hz, .. } => {
+                frequency("low_hz", low_hz);
+                frequency("high_hz", high_hz);
+                if low_hz >= high_hz {
+                    problems.push(format!("{}.low_hz must be below high_hz", name));
+                }
+            }
+            FilterStage::Notch { freq_hz, q } => {
+                frequency("freq_hz", freq_hz);
+                if !(q > 0.0) {
+                    problems.push(format!("{}.q must be positive, got {}", name, q));
+                }
+            }
+            FilterStage::MovingAverage { window } | FilterStage::Median { window } if window == 0 =>
+                problems.push(format!("{}.window must be at least 1", name)),
+            FilterStage::SavitzkyGolay { window, poly_order } => {
+                if window < 3 || window % 2 == 0 {
+                    problems.push(format!("{}.window must be odd and at least 3, got {}", name, window));
+                }
+                if poly_order >= window {
+                    problems.push(format!("{}.poly_order must be below the window, got {}", name, poly_order));
+                }
+            }
+            _ => {}
+        }
+        match *self {
+            FilterStage::LowPass { order, .. } | FilterStage::HighPass { order, .. } | FilterStage::BandPass { order, .. } if !(1..=8).contains(&order) =>
+                problems.push(format!("{}.order must be between 1 and 8, got {}", name, order)),
+            _ => {}
+        }
+    }
+}
+
+// How the drift is estimated, see processing::Detrender
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DetrendConfig {
+    None,
+    // a·exp(-t/τ) + c, refitted every `refit_secs`
+    Exponential {
+        #[serde(default = "default_refit_secs")]
+        refit_secs: f64,
+    },
+    // Two exponentials for a fast and a slow bleaching component
+    DoubleExponential {
+        #[serde(default = "default_refit_secs")]
+        refit_secs: f64,
+    },
+    // First order high-pass; everything slower than `cutoff_hz` counts as trend
+    HighPass { cutoff_hz: f64 },
+}
+
+fn default_refit_secs() -> f64 {
+    10.0
+}
+
+impl DetrendConfig {
+    fn validate(&self, problems: &mut Vec<String>) {
+        match self {
+            DetrendConfig::Exponential { refit_secs } | DetrendConfig::DoubleExponential { refit_secs } if !(*refit_secs > 0.0) =>
+                problems.push(format!("processing.detrend.refit_secs must be positive, got {}", refit_secs)),
+            DetrendConfig::HighPass { cutoff_hz } if !(*cutoff_hz > 0.0) =>
+                problems.push(format!("processing.detrend.cutoff_hz must be positive, got {}", cutoff_hz)),
+            _ => {}
+        }
+    }
+}
+
+impl ProcessingConfig {
+    // Channels in a model window: signal, isosbestic and ΔF/F when it is derived
+    pub fn window_channels(&self) -> usize {
+        if self.dff { 3 } else { 2 }
+    }
+
+    // Whether the signal and isosbestic as plotted and analysed differ from the raw values
+    pub fn alters_channels(&self) -> bool {
+        !self.filters.is_empty() || self.detrend != DetrendConfig::None
+    }
+
+    fn validate_filters(&self, problems: &mut Vec<String>) {
+        if !(self.sample_rate_hz > 0.0) {
+            problems.push(format!("processing.sample_rate_hz must be positive, got {}", self.sample_rate_hz));
+            return;
+        }
+        if !(self.delay_at_hz >= 0.0 && self.delay_at_hz < self.sample_rate_hz / 2.0) {
+            problems.push(format!("processing.delay_at_hz must be between 0 and the Nyquist frequency, got {}", self.delay_at_hz));
+        }
+        for (ix, chain) in self.filters.iter().enumerate() {
+            let name = format!("processing.filters[{}]", ix);
+            if chain.channel > 1 {
+                problems.push(format!("{}.channel must be 0 (signal) or 1 (isosbestic), got {}", name, chain.channel));
+            } else if self.filters[..ix].iter().any(|c| c.channel == chain.channel) {
+                problems.push(format!("{}.channel {} already has a filter chain", name, chain.channel));
+            }
+            for (stage_ix, stage) in chain.stages.iter().enumerate() {
+                stage.validate(&format!("{}.stages[{}]", name, stage_ix), self.sample_rate_hz, problems);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectionConfig {
+    pub cooldown_secs: u64,
+    // How the top level `rules` combine
+    pub combine_rules: RuleCombine,
+    // The detector whose decisions drive stimulation
+    pub detector: DetectorConfig,
+    // Decide on the detector score's rolling z-score instead of its fixed threshold, see adaptive.rs
+    pub adaptive: AdaptiveConfig,
+    // Conditions a stimulation has to meet, see rules.rs. When empty: the detector fired and TTL is high
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleConfig>,
+    // Candidate detectors run on the same windows. They are only recorded (shadow<N>.csv), never stimulate.
+    // Skipped when empty, toml can't write an empty array after the detector table
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shadow: Vec<ShadowConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveConfig {
+    pub enabled: bool,
+    // Number of recent scores the z-score is computed over
+    pub history: usize,
+    // Scores needed before the adaptive mode can fire at all
+    pub min_history: usize,
+    // Required z-score at rest. Every stimulation raises it by `sigma_inc`, up to `max_sigma`
+    pub base_sigma: f64,
+    pub max_sigma: f64,
+    pub sigma_inc: f64,
+    // Time constant (s) the required z-score decays back to `base_sigma` with
+    pub decay_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    // Identifies this detector's rows in the shadow CSV
+    pub name: String,
+    // Candidate model, checked against its metadata like model.path. The session model when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<PathBuf>,
+    // Templates for the candidate. When empty: the candidate model's default template, or the session's
+    // templates if no candidate model is given
+    #[serde(default)]
+    pub templates: Vec<PathBuf>,
+    pub detector: DetectorConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleCombine {
+    All,
+    Any,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    // Shown in the stimulation log when the rule passes
+    pub name: String,
+    // Also passes for this many seconds of data after the condition last held
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub within_secs: Option<f64>,
+    pub when: RuleCondition,
+}
+
+// Conditions are selected by `kind`. Baselines are the one-second rolling averages and standard deviations
+// the input stream keeps for both channels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RuleCondition {
+    // The primary detector fired, on its threshold or on the adaptive z-score when that is enabled
+    Detector,
+    // The primary detector's score is above `above`
+    Score { above: f64 },
+    // Mean of the newest `recent` window samples, in baseline standard deviations from the baseline
+    ChannelZ {
+        #[serde(default)]
+        channel: usize,
+        #[serde(default = "default_recent")]
+        recent: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        above: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        below: Option<f64>,
+    },
+    // More than `fraction` of both channels' window is below baseline + `sigma` standard deviations
+    ChannelPercentile {
+        #[serde(default = "default_sigma")]
+        sigma: f64,
+        fraction: f64,
+    },
+    // The window is usable: finite, not flat (`min_std`), not saturated (`max_abs`) and without dropouts
+    // (`max_gap_secs` between samples)
+    SignalQuality {
+        #[serde(default)]
+        channel: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_std: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_abs: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_gap_secs: Option<f64>,
+    },
+    Ttl {
+        #[serde(default = "default_true")]
+        high: bool,
+    },
+    All { rules: Vec<RuleConfig> },
+    Any { rules: Vec<RuleConfig> },
+}
+
+fn default_sigma() -> f64 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn validate_channels(channels: &[ChannelConfig], problems: &mut Vec<String>) {
+    for (ix, channel) in channels.iter().enumerate() {
+        if channel.name.trim().is_empty() {
+            problems.push(format!("channels[{}].name must not be empty", ix));
+        } else if channels[..ix].iter().any(|c| c.name == channel.name) {
+            problems.push(format!("channels[{}].name '{}' is used twice", ix, channel.name));
+        }
+        if matches!(channel.role, ChannelRole::Derived | ChannelRole::Reward) {
+            problems.push(format!("channels[{}] ('{}') can't be {:?}, inputs are signal, isosbestic or ttl", ix, channel.name, channel.role));
+        }
+    }
+    for role in [ChannelRole::Signal, ChannelRole::Isosbestic] {
+        if !channels.iter().any(|c| c.role == role) {
+            problems.push(format!("channels needs a {:?} channel for the model", role));
+        }
+    }
+    if channels.iter().filter(|c| c.role == ChannelRole::Ttl).count() > 1 {
+        problems.push(String::from("channels can have at most one ttl channel"));
+    }
+}
+
+fn validate_rules(name: &str, rules: &[RuleConfig], window_len: usize, channels: usize, problems: &mut Vec<String>) {
+    for (ix, rule) in rules.iter().enumerate() {
+        let name = format!("{}[{}]", name, ix);
+        if rule.name.trim().is_empty() {
+            problems.push(format!("{}.name must not be empty", name));
+        }
+        if let Some(within) = rule.within_secs.filter(|w| !(*w > 0.0)) {
+            problems.push(format!("{}.within_secs must be positive, got {}", name, within));
+        }
+        let when = format!("{}.when", name);
+        match &rule.when {
+            RuleCondition::Detector | RuleCondition::Ttl { .. } => {}
+            RuleCondition::Score { above } => {
+                if !above.is_finite() {
+                    problems.push(format!("{}.above must be a finite number", when));
+                }
+            }
+            RuleCondition::ChannelZ { channel, recent, above, below } => {
+                if *recent == 0 || *recent > window_len {
+                    problems.push(format!("{}.recent must be between 1 and window_len, got {}", when, recent));
+                }
+                if above.is_none() && below.is_none() {
+                    problems.push(format!("{} needs `above`, `below` or both", when));
+                }
+                if *channel > 1 {
+                    problems.push(format!("{}.channel must be 0 or 1, only the raw channels have rolling baselines", when));
+                }
+            }
+            RuleCondition::ChannelPercentile { fraction, .. } => {
+                if !(*fraction >= 0.0 && *fraction < 1.0) {
+                    problems.push(format!("{}.fraction must be between 0 and 1, got {}", when, fraction));
+                }
+            }
+            RuleCondition::SignalQuality { channel, .. } => validate_channel(&when, *channel, channels, problems),
+            RuleCondition::All { rules } | RuleCondition::Any { rules } => {
+                if rules.is_empty() {
+                    problems.push(format!("{}.rules must not be empty", when));
+                }
+                validate_rules(&format!("{}.rules", when), rules, window_len, channels, problems);
+            }
+        }
+    }
+}
+
+// Detectors are selected by `kind`. Each score is compared against that detector's own threshold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DetectorConfig {
+    // Distance from the model's embedding to the nearest template (needs the model)
+    Embedding { threshold: f64 },
+    // Standard deviations the newest `recent` samples sit above the window
+    #[serde(rename = "zscore")]
+    ZScore {
+        #[serde(default)]
+        channel: usize,
+        #[serde(default = "default_recent")]
+        recent: usize,
+        threshold: f64,
+    },
+    // Prominence of the tallest peak in the window, in signal units
+    PeakProminence {
+        #[serde(default)]
+        channel: usize,
+        threshold: f64,
+    },
+    // Best normalized cross-correlation with a kernel file (same format as templates), in [-1, 1]
+    MatchedFilter {
+        #[serde(default)]
+        channel: usize,
+        kernel: PathBuf,
+        threshold: f64,
+    },
+}
+
+fn default_recent() -> usize {
+    4
+}
+
+impl DetectorConfig {
+    pub fn threshold(&self) -> f64 {
+        match self {
+            DetectorConfig::Embedding { threshold }
+            | DetectorConfig::ZScore { threshold, .. }
+            | DetectorConfig::PeakProminence { threshold, .. }
+            | DetectorConfig::MatchedFilter { threshold, .. } => *threshold,
+        }
+    }
+
+    pub fn set_threshold(&mut self, value: f64) {
+        match self {
+            DetectorConfig::Embedding { threshold }
+            | DetectorConfig::ZScore { threshold, .. }
+            | DetectorConfig::PeakProminence { threshold, .. }
+            | DetectorConfig::MatchedFilter { threshold, .. } => *threshold = value,
+        }
+    }
+
+    // The `kind` it is configured with
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DetectorConfig::Embedding { .. } => "embedding",
+            DetectorConfig::ZScore { .. } => "zscore",
+            DetectorConfig::PeakProminence { .. } => "peak_prominence",
+            DetectorConfig::MatchedFilter { .. } => "matched_filter",
+        }
+    }
+
+    pub fn uses_model(&self) -> bool {
+        matches!(self, DetectorConfig::Embedding { .. })
+    }
+
+    fn validate(&self, name: &str, window_len: usize, channels: usize, problems: &mut Vec<String>) {
+        if !self.threshold().is_finite() {
+            problems.push(format!("{}.threshold must be a finite number", name));
+        }
+        match self {
+            DetectorConfig::Embedding { .. } => {}
+            DetectorConfig::ZScore { channel, recent, .. } => {
+                if *recent == 0 || *recent >= window_len {
+                    problems.push(format!("{}.recent must be between 1 and window_len - 1, got {}", name, recent));
+                }
+                validate_channel(name, *channel, channels, problems);
+            }
+            DetectorConfig::PeakProminence { channel, .. } => validate_channel(name, *channel, channels, problems),
+            DetectorConfig::MatchedFilter { channel, kernel, .. } => {
+                if !kernel.exists() {
+                    problems.push(format!("{}.kernel {:?} does not exist", name, kernel));
+                }
+                validate_channel(name, *channel, channels, problems);
+            }
+        }
+    }
+}
+
+// 0 signal, 1 isosbestic, 2 ΔF/F (only with processing.dff)
+fn validate_channel(name: &str, channel: usize, channels: usize, problems: &mut Vec<String>) {
+    if channel >= channels {
+        let allowed = if channels > 2 { "0, 1 or 2" } else { "0 or 1 (2 is ΔF/F and needs processing.dff)" };
+        problems.push(format!("{}.channel must be {}, got {}", name, allowed, channel));
+    }
+}
+
+// The pulse train protocol sent to the stimulator when the session starts, see stim.rs for the commands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StimulationConfig {
+    pub pulse_width_ms: f64,
+    // Pulses per second within a train
+    pub frequency_hz: f64,
+    pub train_duration_ms: f64,
+    // Trains delivered for every stimulation
+    pub trains: u32,
+    // Gap between the end of one train and the start of the next
+    pub inter_train_interval_ms: f64,
+    // How long to wait for the stimulator to accept the protocol
+    pub ack_timeout_ms: u64,
+}
+
+impl StimulationConfig {
+    pub fn period_ms(&self) -> f64 {
+        1000.0 / self.frequency_hz
+    }
+
+    pub fn pulses_per_train(&self) -> u32 {
+        (self.train_duration_ms / self.period_ms()).floor() as u32
+    }
+
+    // Time the trains of one stimulation run for, not counting the gaps between them
+    pub fn stim_secs(&self) -> f64 {
+        self.trains as f64 * self.train_duration_ms / 1000.0
+    }
+
+    // First train to the end of the last, gaps included. The stimulator can't take another STIM before that
+    pub fn protocol_secs(&self) -> f64 {
+        (self.trains as f64 * self.train_duration_ms + self.trains.saturating_sub(1) as f64 * self.inter_train_interval_ms) / 1000.0
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        if !(self.frequency_hz > 0.0) {
+            problems.push(format!("stimulation.frequency_hz must be positive, got {}", self.frequency_hz));
+            return;
+        }
+        if !(self.pulse_width_ms > 0.0) || self.pulse_width_ms >= self.period_ms() {
+            problems.push(format!("stimulation.pulse_width_ms must be between 0 and the pulse period ({} ms), got {}",
+                self.period_ms(), self.pulse_width_ms));
+        }
+        if self.pulses_per_train() == 0 {
+            problems.push(format!("stimulation.train_duration_ms of {} is shorter than one pulse period ({} ms)",
+                self.train_duration_ms, self.period_ms()));
+        }
+        if self.trains == 0 {
+            problems.push(String::from("stimulation.trains must be at least 1"));
+        }
+        if !(self.inter_train_interval_ms >= 0.0) {
+            problems.push(format!("stimulation.inter_train_interval_ms must not be negative, got {}", self.inter_train_interval_ms));
+        }
+    }
+}
+
+impl AdaptiveConfig {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.min_history < 2 || self.min_history > self.history {
+            problems.push(format!("detection.adaptive.min_history must be between 2 and history ({}), got {}",
+                self.history, self.min_history));
+        }
+        if !(self.base_sigma <= self.max_sigma) {
+            problems.push(format!("detection.adaptive.base_sigma ({}) must not be above max_sigma ({})", self.base_sigma, self.max_sigma));
+        }
+        if !(self.sigma_inc >= 0.0) {
+            problems.push(format!("detection.adaptive.sigma_inc must not be negative, got {}", self.sigma_inc));
+        }
+        if !(self.decay_secs > 0.0) {
+            problems.push(format!("detection.adaptive.decay_secs must be positive, got {}", self.decay_secs));
+        }
+    }
+}
+
+// Limits enforced on every stimulation on top of the cooldown, see safety.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyConfig {
+    pub max_per_minute: u32,
+    pub max_per_hour: u32,
+    pub max_per_session: u32,
+    // Total train time over the session, see StimulationConfig::stim_secs
+    pub max_stim_secs: f64,
+    // Whether closed-loop stimulation is armed when the session starts, it can be toggled in the sidebar
+    pub start_armed: bool,
+}
+
+// Threshold calibration from a baseline period without stimulation, see calibration.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationConfig {
+    // Data time the detector scores are recorded for
+    pub baseline_secs: f64,
+    // Histogram bins shown in the sidebar
+    pub bins: usize,
+    pub target: CalibrationTarget,
+}
+
+// What the proposed threshold aims for on the baseline
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum CalibrationTarget {
+    // Fraction of baseline windows scoring above the threshold
+    FalsePositiveRate { rate: f64 },
+    // Detections per minute of baseline, counting one per cooldown
+    EventsPerMinute { rate: f64 },
+}
+
+impl CalibrationTarget {
+    fn validate(&self, problems: &mut Vec<String>) {
+        match self {
+            CalibrationTarget::FalsePositiveRate { rate } if !(*rate > 0.0 && *rate < 1.0) =>
+                problems.push(format!("calibration.target.rate must be between 0 and 1 for a false positive rate, got {}", rate)),
+            CalibrationTarget::EventsPerMinute { rate } if !(*rate > 0.0) =>
+                problems.push(format!("calibration.target.rate must be positive, got {}", rate)),
+            _ => {}
+        }
+    }
+}
+
+// Experimental condition. Only `label` is shown during the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConditionConfig {
+    pub label: String,
+    // Seed for the jitter and sham delivery times. Drawn at random when left out and recorded in the sidecar
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    pub protocol: ConditionProtocol,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ConditionProtocol {
+    // Stimulate on the detector and rules
+    ClosedLoop,
+    // Stimulate every `interval_secs` of data, each moved by up to +-`jitter_secs`. The detector still runs and
+    // its decisions are recorded
+    OpenLoop {
+        interval_secs: f64,
+        #[serde(default)]
+        jitter_secs: f64,
+    },
+    // Record every decision that would have stimulated, never stimulate
+    ShamSuppress,
+    // Record every decision that would have stimulated and deliver one stimulation for each, at a random delay
+    // between `min_delay_secs` and `max_delay_secs` after it
+    ShamMatched { min_delay_secs: f64, max_delay_secs: f64 },
+}
+
+impl ConditionConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let text = toml::to_string_pretty(self).map_err(ConfigError::Serialize)?;
+        fs::write(path, text).map_err(|e| ConfigError::Io(path.to_path_buf(), e))
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        match self.protocol {
+            ConditionProtocol::OpenLoop { interval_secs, jitter_secs } => {
+                if !(interval_secs > 0.0) {
+                    problems.push(format!("condition.protocol.interval_secs must be positive, got {}", interval_secs));
+                }
+                if !(jitter_secs >= 0.0 && jitter_secs < interval_secs) {
+                    problems.push(format!("condition.protocol.jitter_secs must be between 0 and interval_secs, got {}", jitter_secs));
+                }
+            }
+            ConditionProtocol::ShamMatched { min_delay_secs, max_delay_secs } => {
+                if !(min_delay_secs >= 0.0 && min_delay_secs <= max_delay_secs) {
+                    problems.push(format!("condition.protocol delays must satisfy 0 <= min_delay_secs <= max_delay_secs, got {} and {}",
+                        min_delay_secs, max_delay_secs));
+                }
+            }
+            ConditionProtocol::ClosedLoop | ConditionProtocol::ShamSuppress => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub path: PathBuf,
+    // Inference runtime, see model::load_model
+    pub backend: ModelBackend,
+    // Template files (.json or .npy) describing the events to stimulate on, see templates.rs. When empty the
+    // model's default template from its metadata file is used
+    pub templates: Vec<PathBuf>,
+    // Where templates captured from the live plot are saved
+    pub template_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelBackend {
+    // By file extension
+    Auto,
+    // TorchScript through libtorch
+    Torch,
+    // ONNX through tract, pure Rust
+    Onnx,
+}
+
+impl Default for RasaConfig {
+    fn default() -> Self {
+        Self {
+            session: SessionConfig::default(),
+            display: DisplayConfig::default(),
+            acquisition: AcquisitionConfig::default(),
+            processing: ProcessingConfig::default(),
+            detection: DetectionConfig::default(),
+            stimulation: StimulationConfig::default(),
+            safety: SafetyConfig::default(),
+            calibration: CalibrationConfig::default(),
+            model: ModelConfig::default(),
+            // The photometry rig's `signal isosbestic ttl` lines
+            channels: vec![
+                ChannelConfig::new("signal", "a.u.", ChannelRole::Signal),
+                ChannelConfig::new("isosbestic", "a.u.", ChannelRole::Isosbestic),
+                ChannelConfig::new("ttl", "", ChannelRole::Ttl),
+            ],
+            condition: ConditionConfig::default(),
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self { output_dir: PathBuf::from("data"), subject: String::new(), profile_dir: PathBuf::from("profiles") }
+    }
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self { show_box: true, look_behind: 4 }
+    }
+}
+
+impl Default for AcquisitionConfig {
+    fn default() -> Self {
+        Self { baud_rate: 115200, window_len: 64, window_secs: 2.0, skip: None, channels: None }
+    }
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self {
+            dff: false,
+            fit_window_secs: 60.0,
+            sample_rate_hz: 100.0,
+            delay_at_hz: 1.0,
+            detrend: DetrendConfig::None,
+            filters: Vec::new(),
+        }
+    }
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self {
+            cooldown_secs: 16,
+            combine_rules: RuleCombine::All,
+            detector: DetectorConfig::default(),
+            adaptive: AdaptiveConfig::default(),
+            rules: Vec::new(),
+            shadow: Vec::new(),
+        }
+    }
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self { enabled: false, history: 1024, min_history: 64, base_sigma: 3.7, max_sigma: 5.5, sigma_inc: 0.05, decay_secs: 300.0 }
+    }
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig::Embedding { threshold: 300.0 }
+    }
+}
+
+impl Default for StimulationConfig {
+    fn default() -> Self {
+        Self {
+            pulse_width_ms: 5.0,
+            frequency_hz: 20.0,
+            train_duration_ms: 1000.0,
+            trains: 1,
+            inter_train_interval_ms: 0.0,
+            ack_timeout_ms: 1000,
+        }
+    }
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self { max_per_minute: 4, max_per_hour: 60, max_per_session: 300, max_stim_secs: 600.0, start_armed: true }
+    }
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        Self { baseline_secs: 300.0, bins: 40, target: CalibrationTarget::FalsePositiveRate { rate: 0.01 } }
+    }
+}
+
+impl Default for ConditionConfig {
+    fn default() -> Self {
+        Self { label: String::new(), seed: None, protocol: ConditionProtocol::ClosedLoop }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("models/nested_model4.pt"),
+            backend: ModelBackend::Auto,
+            templates: Vec::new(),
+            template_dir: PathBuf::from("templates"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Serialize(toml::ser::Error),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not access config {:?}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse config {:?}: {}", path, e),
+            ConfigError::Serialize(e) => write!(f, "could not write config: {}", e),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl RasaConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    // Check every value and report all of the problems at once rather than just the first
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.display.look_behind == 0 {
+            problems.push(String::from("display.look_behind must be at least 1 second"));
+        }
+        if self.acquisition.baud_rate == 0 {
+            problems.push(String::from("acquisition.baud_rate must be positive"));
+        }
+        if self.acquisition.window_len < 2 {
+            problems.push(format!("acquisition.window_len must be at least 2, got {}", self.acquisition.window_len));
+        }
+        if !(self.acquisition.window_secs > 0.0 && self.acquisition.window_secs <= MAX_WINDOW_SECS) {
+            problems.push(format!("acquisition.window_secs must be positive and at most {}, got {}", MAX_WINDOW_SECS, self.acquisition.window_secs));
+        }
+        if self.acquisition.skip.is_some() {
+            problems.push(String::from("acquisition.skip has been replaced by acquisition.window_secs, the model window's duration"));
+        }
+        if self.acquisition.channels.is_some() {
+            problems.push(String::from("acquisition.channels has been replaced by [[channels]], the plotted series follow from it"));
+        }
+        validate_channels(&self.channels, &mut problems);
+        let channels = self.processing.window_channels();
+        if !(self.processing.fit_window_secs > 0.0) {
+            problems.push(format!("processing.fit_window_secs must be positive, got {}", self.processing.fit_window_secs));
+        }
+        self.processing.detrend.validate(&mut problems);
+        self.processing.validate_filters(&mut problems);
+        self.detection.detector.validate("detection.detector", self.acquisition.window_len, channels, &mut problems);
+        self.detection.adaptive.validate(&mut problems);
+        validate_rules("detection.rules", &self.detection.rules, self.acquisition.window_len, channels, &mut problems);
+        for (ix, shadow) in self.detection.shadow.iter().enumerate() {
+            let name = format!("detection.shadow[{}]", ix);
+            if shadow.name.trim().is_empty() {
+                problems.push(format!("{}.name must not be empty", name));
+            } else if self.detection.shadow[..ix].iter().any(|s| s.name == shadow.name) {
+                problems.push(format!("{}.name '{}' is used twice", name, shadow.name));
+            }
+            shadow.detector.validate(&format!("{}.detector", name), self.acquisition.window_len, channels, &mut problems);
+            if let Some(model) = shadow.model.as_ref().filter(|m| !m.exists()) {
+                problems.push(format!("{}.model {:?} does not exist", name, model));
+            }
+            for template in shadow.templates.iter().filter(|t| !t.exists()) {
+                problems.push(format!("{}: template {:?} does not exist", name, template));
+            }
+        }
+        self.stimulation.validate(&mut problems);
+        if self.safety.max_per_minute == 0 || self.safety.max_per_hour == 0 || self.safety.max_per_session == 0 {
+            problems.push(String::from("safety limits must allow at least one stimulation"));
+        }
+        if !(self.detection.cooldown_secs as f64 >= self.stimulation.protocol_secs()) {
+            problems.push(format!("detection.cooldown_secs ({}) is shorter than a stimulation protocol ({} s), so trains would overlap",
+                self.detection.cooldown_secs, self.stimulation.protocol_secs()));
+        }
+        if !(self.safety.max_stim_secs >= self.stimulation.stim_secs()) {
+            problems.push(format!("safety.max_stim_secs ({}) is shorter than a single stimulation ({} s)",
+                self.safety.max_stim_secs, self.stimulation.stim_secs()));
+        }
+        if !(self.calibration.baseline_secs > 0.0) {
+            problems.push(format!("calibration.baseline_secs must be positive, got {}", self.calibration.baseline_secs));
+        }
+        if self.calibration.bins == 0 {
+            problems.push(String::from("calibration.bins must be at least 1"));
+        }
+        self.calibration.target.validate(&mut problems);
+        self.condition.validate(&mut problems);
+        if self.session.subject.contains(|c: char| std::path::is_separator(c)) {
+            problems.push(format!("session.subject '{}' must not contain path separators", self.session.subject));
+        }
+        if self.detection.detector.uses_model() && !self.model.path.exists() {
+            problems.push(format!("model.path {:?} does not exist", self.model.path));
+        }
+        for template in self.model.templates.iter().filter(|t| !t.exists()) {
+            problems.push(format!("template {:?} does not exist", template));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    // Write the effective configuration (file plus command line overrides) so the session can be rerun exactly
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let text = toml::to_string_pretty(self).map_err(ConfigError::Serialize)?;
+        fs::write(path, text).map_err(|e| ConfigError::Io(path.to_path_buf(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_file_uses_defaults() {
+        let config: RasaConfig = toml::from_str("[detection.detector]\nkind = \"embedding\"\nthreshold = 250.0\n").unwrap();
+        assert_eq!(config.detection.detector.threshold(), 250.0);
+        assert_eq!(config.detection.cooldown_secs, 16);
+        assert_eq!(config.acquisition.window_len, 64);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<RasaConfig>("[detection]\ncooldown = 16\n").is_err());
+        assert!(toml::from_str::<RasaConfig>("[detection.detector]\nkind = \"embedding\"\nthreshhold = 250.0\n").is_err());
+    }
+
+    #[test]
+    fn selects_detector_by_kind() {
+        let config: RasaConfig = toml::from_str("[detection.detector]\nkind = \"zscore\"\nthreshold = 3.0\n").unwrap();
+        match config.detection.detector {
+            DetectorConfig::ZScore { channel, recent, threshold } => assert_eq!((channel, recent, threshold), (0, 4, 3.0)),
+            other => panic!("expected z-score detector, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_shadow_detectors() {
+        let text = "[[detection.shadow]]\nname = \"candidate\"\nmodel = \"models/nested_model3.pt\"\n\
+            [detection.shadow.detector]\nkind = \"embedding\"\nthreshold = 250.0\n\n\
+            [[detection.shadow]]\nname = \"prominence\"\ndetector = { kind = \"peak_prominence\", threshold = 2.0 }\n";
+        let config: RasaConfig = toml::from_str(text).unwrap();
+        assert_eq!(config.detection.shadow.len(), 2);
+        assert_eq!(config.detection.shadow[0].model, Some(PathBuf::from("models/nested_model3.pt")));
+        assert!(config.detection.shadow[1].templates.is_empty());
+        assert!(toml::from_str::<RasaConfig>(&toml::to_string_pretty(&config).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config = RasaConfig::default();
+        config.acquisition.window_secs = 0.0;
+        config.acquisition.baud_rate = 0;
+        config.model.templates.push(PathBuf::from("templates/missing.json"));
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert!(problems.len() >= 3),
+            other => panic!("expected invalid config, got {:?}", other),
+        }
+        let mut config = RasaConfig::default();
+        config.acquisition.window_secs = MAX_WINDOW_SECS * 2.0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(problems)) if problems.iter().any(|p| p.contains("window_secs"))));
+        // Three 1 s trains 2 s apart take 7 s
+        let mut config = RasaConfig::default();
+        (config.stimulation.trains, config.stimulation.inter_train_interval_ms, config.detection.cooldown_secs) = (3, 2000.0, 6);
+        assert_eq!(config.stimulation.protocol_secs(), 7.0);
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(problems)) if problems.iter().any(|p| p.contains("cooldown_secs"))));
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut config = RasaConfig::default();
+        config.model.templates.push(PathBuf::from("templates/peak32.json"));
+        let text = toml::to_string_pretty(&config).unwrap();
+        let back: RasaConfig = toml::from_str(&text).unwrap();
+        assert_eq!(back.model.templates, config.model.templates);
+    }
+}
@@ -0,0 +1,299 @@
+use std::error::Error;
+use std::sync::*;
+use std::time::{Duration, Instant};
+
+use crate::config::DetectorConfig;
+use crate::model::SharedModel;
+use crate::templates::{nearest, Template};
+
+// The most recent model window: the last `window_secs` of both channels and ΔF/F (empty unless processing.dff
+// is set), resampled to `window_len` evenly spaced points, and their times
+pub struct Window<'a> {
+    pub times: &'a [f64],
+    pub v0: &'a [f64],
+    pub v1: &'a [f64],
+    pub dff: &'a [f64],
+}
+
+impl<'a> Window<'a> {
+    pub fn channel(&self, ix: usize) -> &'a [f64] {
+        match ix {
+            0 => self.v0,
+            1 => self.v1,
+            _ => self.dff,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Detection {
+    pub score: f64,
+    // Whether the score crossed the detector's threshold
+    pub fire: bool,
+    // What was detected, e.g. the name of the matched template
+    pub label: String,
+}
+
+pub trait Detector: Send {
+    fn name(&self) -> String;
+    fn threshold(&self) -> f64;
+    fn set_threshold(&mut self, threshold: f64);
+    fn detect(&mut self, window: &Window) -> Result<Detection, Box<dyn Error>>;
+}
+
+// Distance of the model's embedding of the window to the nearest template. This is the original Rasa detector.
+// The model can be swapped from the GUI mid-session, see selector.rs
+pub struct EmbeddingDetector {
+    model: SharedModel,
+    templates: Arc<RwLock<Vec<Template>>>,
+    threshold: f64,
+}
+
+impl EmbeddingDetector {
+    pub fn new(model: SharedModel, templates: Arc<RwLock<Vec<Template>>>, threshold: f64) -> Self {
+        Self { model, templates, threshold }
+    }
+}
+
+impl Detector for EmbeddingDetector {
+    fn name(&self) -> String {
+        String::from("embedding")
+    }
+
+    fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+
+    fn detect(&mut self, window: &Window) -> Result<Detection, Box<dyn Error>> {
+        let templates = self.templates.read().unwrap();
+        let model = self.model.read().unwrap().clone().ok_or("No model loaded")?;
+        let embedding = model.embed(window.v0, window.v1)?;
+        let (ix, distance) = nearest(&embedding, &templates)?.ok_or("No templates loaded")?;
+        Ok(Detection {
+            score: distance,
+            fire: distance > self.threshold,
+            label: templates[ix].name.clone(),
+        })
+    }
+}
+
+// How many standard deviations the mean of the newest `recent` samples sits above the whole window
+pub struct ZScoreDetector {
+    channel: usize,
+    recent: usize,
+    threshold: f64,
+}
+
+impl ZScoreDetector {
+    pub fn new(channel: usize, recent: usize, threshold: f64) -> Self {
+        Self { channel, recent: recent.max(1), threshold }
+    }
+}
+
+impl Detector for ZScoreDetector {
+    fn name(&self) -> String {
+        format!("zscore[{}]", self.channel)
+    }
+
+    fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+
+    fn detect(&mut self, window: &Window) -> Result<Detection, Box<dyn Error>> {
+        let data = window.channel(self.channel);
+        if data.len() <= self.recent {
+            return Err("Window is shorter than the recent span".into());
+        }
+        let n = data.len() as f64;
+        let mean = data.iter().sum::<f64>() / n;
+        let std = (data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let tail = &data[data.len() - self.recent..];
+        let recent_mean = tail.iter().sum::<f64>() / tail.len() as f64;
+
+        let score = if std > 0.0 { (recent_mean - mean) / std } else { 0.0 };
+        Ok(Detection { score, fire: score > self.threshold, label: String::from("zscore") })
+    }
+}
+
+// Topographic prominence of the tallest peak in the window: its height above the higher of the two
+// minima separating it from the window edges
+pub struct PeakProminenceDetector {
+    channel: usize,
+    threshold: f64,
+}
+
+impl PeakProminenceDetector {
+    pub fn new(channel: usize, threshold: f64) -> Self {
+        Self { channel, threshold }
+    }
+}
+
+impl Detector for PeakProminenceDetector {
+    fn name(&self) -> String {
+        format!("peak_prominence[{}]", self.channel)
+    }
+
+    fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+
+    fn detect(&mut self, window: &Window) -> Result<Detection, Box<dyn Error>> {
+        let data = window.channel(self.channel);
+        let (peak_ix, peak) = data.iter().copied().enumerate()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .ok_or("Empty window")?;
+        let left_base = data[..=peak_ix].iter().copied().fold(f64::INFINITY, f64::min);
+        let right_base = data[peak_ix..].iter().copied().fold(f64::INFINITY, f64::min);
+        let score = peak - left_base.max(right_base);
+
+        Ok(Detection { score, fire: score > self.threshold, label: String::from("peak") })
+    }
+}
+
+// Best normalized cross-correlation of a kernel against the window, in [-1, 1]
+pub struct MatchedFilterDetector {
+    channel: usize,
+    kernel: Template,
+    threshold: f64,
+}
+
+impl MatchedFilterDetector {
+    pub fn new(channel: usize, kernel: Template, threshold: f64) -> Self {
+        Self { channel, kernel, threshold }
+    }
+}
+
+impl Detector for MatchedFilterDetector {
+    fn name(&self) -> String {
+        format!("matched_filter[{}]", self.channel)
+    }
+
+    fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+
+    fn detect(&mut self, window: &Window) -> Result<Detection, Box<dyn Error>> {
+        let data = window.channel(self.channel);
+        let kernel = &self.kernel.vector;
+        if data.len() < kernel.len() {
+            return Err(format!("Window of {} is shorter than kernel '{}' of {}", data.len(), self.kernel.name, kernel.len()).into());
+        }
+
+        let k_mean = kernel.iter().sum::<f64>() / kernel.len() as f64;
+        let k_norm = kernel.iter().map(|k| (k - k_mean).powi(2)).sum::<f64>().sqrt();
+        let score = data.windows(kernel.len()).map(|segment| {
+            let s_mean = segment.iter().sum::<f64>() / segment.len() as f64;
+            let s_norm = segment.iter().map(|s| (s - s_mean).powi(2)).sum::<f64>().sqrt();
+            if s_norm == 0.0 || k_norm == 0.0 {
+                return 0.0;
+            }
+            segment.iter().zip(kernel).map(|(s, k)| (s - s_mean) * (k - k_mean)).sum::<f64>() / (s_norm * k_norm)
+        }).fold(f64::NEG_INFINITY, f64::max);
+
+        Ok(Detection { score, fire: score > self.threshold, label: self.kernel.name.clone() })
+    }
+}
+
+// Build a detector from its config. Only the embedding detector needs the model, so `model` may be empty
+// when libtorch is unavailable
+pub fn build_detector(
+    detector: &DetectorConfig,
+    model: &SharedModel,
+    templates: &Arc<RwLock<Vec<Template>>>,
+) -> Result<Box<dyn Detector>, Box<dyn Error>> {
+    Ok(match detector {
+        DetectorConfig::Embedding { threshold } => {
+            if model.read().unwrap().is_none() {
+                return Err("The embedding detector needs a model but none is loaded".into());
+            }
+            Box::new(EmbeddingDetector::new(Arc::clone(model), Arc::clone(templates), *threshold))
+        }
+        DetectorConfig::ZScore { channel, recent, threshold } =>
+            Box::new(ZScoreDetector::new(*channel, *recent, *threshold)),
+        DetectorConfig::PeakProminence { channel, threshold } =>
+            Box::new(PeakProminenceDetector::new(*channel, *threshold)),
+        DetectorConfig::MatchedFilter { channel, kernel, threshold } =>
+            Box::new(MatchedFilterDetector::new(*channel, Template::load(kernel)?, *threshold)),
+    })
+}
+
+// A candidate detector that sees the same windows as the primary one but can never stimulate. It keeps its
+// own cooldown so its would-be stimulations follow the same rules as the primary's real ones
+pub struct ShadowDetector {
+    pub name: String,
+    detector: Box<dyn Detector>,
+    zapper_timer: Instant,
+}
+
+impl ShadowDetector {
+    pub fn new(name: String, detector: Box<dyn Detector>) -> Self {
+        Self { name, detector, zapper_timer: Instant::now() }
+    }
+
+    pub fn detector_name(&self) -> String {
+        self.detector.name()
+    }
+
+    // The detection and whether it would have led to a stimulation had this been the primary detector. Takes
+    // the session's current cooldown, as set in the sidebar
+    pub fn evaluate(&mut self, window: &Window, ttl: bool, cooldown: Duration) -> Result<(Detection, bool), Box<dyn Error>> {
+        let detection = self.detector.detect(window)?;
+        let mut would_stimulate = false;
+        if detection.fire && self.zapper_timer.elapsed() > cooldown {
+            self.zapper_timer = Instant::now();
+            would_stimulate = ttl;
+        }
+        Ok((detection, would_stimulate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn window<'a>(times: &'a [f64], v0: &'a [f64]) -> Window<'a> {
+        Window { times, v0, v1: v0, dff: &[] }
+    }
+
+    #[test]
+    fn zscore_fires_on_recent_rise() {
+        let times: Vec<f64> = (0..20).map(|i| i as f64).collect();
+        let mut v0 = vec![0.0, 1.0].repeat(8);
+        v0.extend([10.0; 4]);
+        let detection = ZScoreDetector::new(0, 4, 1.5).detect(&window(&times, &v0)).unwrap();
+        assert!(detection.fire, "score was {}", detection.score);
+    }
+
+    #[test]
+    fn prominence_uses_higher_base() {
+        let v0 = [5.0, 1.0, 9.0, 3.0, 4.0];
+        let detection = PeakProminenceDetector::new(0, 5.0).detect(&window(&v0, &v0)).unwrap();
+        assert_eq!(detection.score, 6.0);
+        assert!(detection.fire);
+    }
+
+    #[test]
+    fn matched_filter_finds_scaled_kernel() {
+        let kernel = Template { name: String::from("k"), vector: vec![0.0, 1.0, 0.5, 0.25] };
+        let v0 = [3.0, 3.0, 3.0, 13.0, 8.0, 5.5, 3.0, 3.0];
+        let detection = MatchedFilterDetector::new(0, kernel, 0.9).detect(&window(&v0, &v0)).unwrap();
+        assert!((detection.score - 1.0).abs() < 1e-9);
+        assert!(detection.fire);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::*;
+use csv::Writer;
+use tracing::error;
+
+use crate::util::unix_timestamp_ms;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    StimDelivered,
+    StimBlocked,
+    TtlRise,
+    TtlFall,
+    ModelSwap,
+    ParameterChange,
+    EStop,
+    Rearm,
+    Annotation,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EventKind::StimDelivered => "stim_delivered",
+            EventKind::StimBlocked => "stim_blocked",
+            EventKind::TtlRise => "ttl_rise",
+            EventKind::TtlFall => "ttl_fall",
+            EventKind::ModelSwap => "model_swap",
+            EventKind::ParameterChange => "parameter_change",
+            EventKind::EStop => "estop",
+            EventKind::Rearm => "rearm",
+            EventKind::Annotation => "annotation",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Per-session events<N>.csv: data time, host unix time (ms), event kind, detail. Data time is the time of the
+// newest sample the input stream has produced, so events line up with the data<N>.csv rows around them.
+// Cheap to clone, every thread that has something to record gets its own handle. Each row is written and
+// flushed before `log` returns, so a crash or exit doesn't lose it. Events are rare enough for that to cost
+// nothing. The newest events are also kept in memory for the plot markers
+#[derive(Clone)]
+pub struct EventLog {
+    writer: Arc<Mutex<Writer<File>>>,
+    clock: Arc<Mutex<f64>>,
+    recent: Arc<Mutex<VecDeque<(f64, EventKind)>>>,
+}
+
+const RECENT_EVENTS: usize = 512;
+
+impl EventLog {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().write(true).create(true).append(true).open(path)?;
+        let new = file.metadata()?.len() == 0;
+        let mut writer = Writer::from_writer(file);
+        if new {
+            writer.write_record(["data_time", "unix_ms", "event", "detail"])?;
+            writer.flush()?;
+        }
+
+        Ok(Self { writer: Arc::new(Mutex::new(writer)), clock: Arc::new(Mutex::new(0.0)), recent: Arc::new(Mutex::new(VecDeque::new())) })
+    }
+
+    // Called by the input stream with every sample's time
+    pub fn set_time(&self, time: f64) {
+        *self.clock.lock().unwrap() = time;
+    }
+
+    pub fn time(&self) -> f64 {
+        *self.clock.lock().unwrap()
+    }
+
+    pub fn log(&self, kind: EventKind, detail: impl Into<String>) {
+        let time = self.time();
+        {
+            let mut recent = self.recent.lock().unwrap();
+            recent.push_back((time, kind));
+            if recent.len() > RECENT_EVENTS {
+                recent.pop_front();
+            }
+        }
+        let mut writer = self.writer.lock().unwrap();
+        let result = writer.write_record(&[time.to_string(), unix_timestamp_ms().to_string(), kind.to_string(), detail.into()])
+            .and_then(|_| writer.flush().map_err(csv::Error::from));
+        if let Err(e) = result {
+            error!("Could not write to the event log: {}", e);
+        }
+    }
+
+    // Events at or after `since`, oldest first
+    pub fn recent(&self, since: f64) -> Vec<(f64, EventKind)> {
+        self.recent.lock().unwrap().iter().filter(|(t, _)| *t >= since).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rows_are_on_disk_once_logged() {
+        let path = std::env::temp_dir().join(format!("rasa_events_{}.csv", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let events = EventLog::open(&path).unwrap();
+        events.set_time(1.5);
+        events.log(EventKind::Annotation, "hello");
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "data_time,unix_ms,event,detail");
+        assert!(lines[1].starts_with("1.5,") && lines[1].ends_with(",annotation,hello"), "{}", lines[1]);
+
+        // Reopening appends without a second header
+        EventLog::open(&path).unwrap().log(EventKind::EStop, "");
+        assert_eq!(std::fs::read_to_string(&path).unwrap().matches("data_time").count(), 1);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::config::{FilterChainConfig, FilterStage};
+use crate::util::solve_linear;
+
+// A causal filter fed one sample at a time
+pub trait Filter: Send {
+    fn push(&mut self, x: f64) -> f64;
+    // Group delay in samples at `omega` (radians per sample)
+    fn group_delay(&self, omega: f64) -> f64;
+}
+
+// The stages configured for one channel, run in order
+pub struct FilterChain {
+    stages: Vec<Box<dyn Filter>>,
+    sample_rate_hz: f64,
+    last: Option<f64>,
+}
+
+impl FilterChain {
+    pub fn new(config: &FilterChainConfig, sample_rate_hz: f64) -> Self {
+        let stages = config.stages.iter().map(|stage| build(stage, sample_rate_hz)).collect();
+        Self { stages, sample_rate_hz, last: None }
+    }
+
+    // A NaN or inf would stay in the stages' state for good, so those samples hold the last output instead
+    pub fn push(&mut self, x: f64) -> f64 {
+        if !x.is_finite() {
+            return self.last.unwrap_or(x);
+        }
+        let y = self.stages.iter_mut().fold(x, |x, stage| stage.push(x));
+        self.last = Some(y);
+        y
+    }
+
+    // Delay the chain adds at `freq_hz`, in seconds
+    pub fn group_delay_secs(&self, freq_hz: f64) -> f64 {
+        let omega = 2.0 * PI * freq_hz / self.sample_rate_hz;
+        self.stages.iter().map(|stage| stage.group_delay(omega)).sum::<f64>() / self.sample_rate_hz
+    }
+}
+
+fn build(stage: &FilterStage, fs: f64) -> Box<dyn Filter> {
+    match *stage {
+        FilterStage::LowPass { cutoff_hz, order } => Box::new(Biquads::butterworth(cutoff_hz / fs, order, false)),
+        FilterStage::HighPass { cutoff_hz, order } => Box::new(Biquads::butterworth(cutoff_hz / fs, order, true)),
+        FilterStage::BandPass { low_hz, high_hz, order } => {
+            let mut sections = Biquads::butterworth(low_hz / fs, order, true);
+            sections.sections.extend(Biquads::butterworth(high_hz / fs, order, false).sections);
+            Box::new(sections)
+        }
+        FilterStage::Notch { freq_hz, q } => Box::new(Biquads { sections: vec![Biquad::notch(freq_hz / fs, q)] }),
+        FilterStage::MovingAverage { window } => Box::new(MovingAverage { window, values: VecDeque::with_capacity(window + 1), sum: 0.0 }),
+        FilterStage::Median { window } => Box::new(Median { window, values: VecDeque::with_capacity(window + 1) }),
+        FilterStage::SavitzkyGolay { window, poly_order } => Box::new(SavitzkyGolay::new(window, poly_order)),
+    }
+}
+
+// Second order section, direct form I. First order sections leave b2 and a2 at zero
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self { b: b.map(|v| v / a[0]), a: [a[1] / a[0], a[2] / a[0]], x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    // Cookbook low/high-pass at normalized frequency `f` (cycles per sample)
+    fn pass(f: f64, q: f64, high: bool) -> Self {
+        let w0 = 2.0 * PI * f;
+        let (cos, alpha) = (w0.cos(), w0.sin() / (2.0 * q));
+        let a = [1.0 + alpha, -2.0 * cos, 1.0 - alpha];
+        if high {
+            Self::new([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], a)
+        } else {
+            Self::new([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], a)
+        }
+    }
+
+    // Bilinear first order section, for odd orders
+    fn first_order(f: f64, high: bool) -> Self {
+        let k = (PI * f).tan();
+        let a = [1.0, (k - 1.0) / (k + 1.0), 0.0];
+        if high {
+            Self::new([1.0 / (1.0 + k), -1.0 / (1.0 + k), 0.0], a)
+        } else {
+            Self::new([k / (1.0 + k), k / (1.0 + k), 0.0], a)
+        }
+    }
+
+    fn notch(f: f64, q: f64) -> Self {
+        let w0 = 2.0 * PI * f;
+        let (cos, alpha) = (w0.cos(), w0.sin() / (2.0 * q));
+        Self::new([1.0, -2.0 * cos, 1.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    fn push(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    fn group_delay(&self, omega: f64) -> f64 {
+        polynomial_delay(&self.b, omega) - polynomial_delay(&[1.0, self.a[0], self.a[1]], omega)
+    }
+}
+
+// Group delay of the polynomial Σ p_k z^-k at `omega`: Re(Σ k p_k e^-iωk / Σ p_k e^-iωk)
+fn polynomial_delay(p: &[f64], omega: f64) -> f64 {
+    let (mut re, mut im, mut dre, mut dim) = (0.0, 0.0, 0.0, 0.0);
+    for (k, pk) in p.iter().enumerate() {
+        let (c, s) = ((omega * k as f64).cos(), -(omega * k as f64).sin());
+        re += pk * c;
+        im += pk * s;
+        dre += k as f64 * pk * c;
+        dim += k as f64 * pk * s;
+    }
+    let norm = re * re + im * im;
+    if norm < 1e-24 {
+        return 0.0;
+    }
+    (dre * re + dim * im) / norm
+}
+
+struct Biquads {
+    sections: Vec<Biquad>,
+}
+
+impl Biquads {
+    // Butterworth of `order` as cascaded sections, the k-th pole pair with Q = 1 / (2 sin((2k + 1)π / 2n))
+    fn butterworth(f: f64, order: usize, high: bool) -> Self {
+        let mut sections: Vec<Biquad> = (0..order / 2)
+            .map(|k| {
+                let q = 1.0 / (2.0 * ((2 * k + 1) as f64 * PI / (2 * order) as f64).sin());
+                Biquad::pass(f, q, high)
+            })
+            .collect();
+        if order % 2 == 1 {
+            sections.push(Biquad::first_order(f, high));
+        }
+        Self { sections }
+    }
+}
+
+impl Filter for Biquads {
+    fn push(&mut self, x: f64) -> f64 {
+        self.sections.iter_mut().fold(x, |x, section| section.push(x))
+    }
+
+    fn group_delay(&self, omega: f64) -> f64 {
+        self.sections.iter().map(|section| section.group_delay(omega)).sum()
+    }
+}
+
+// Until the windows fill up the FIR stages work on what they have
+struct MovingAverage {
+    window: usize,
+    values: VecDeque<f64>,
+    sum: f64,
+}
+
+impl Filter for MovingAverage {
+    fn push(&mut self, x: f64) -> f64 {
+        self.values.push_back(x);
+        self.sum += x;
+        if self.values.len() > self.window {
+            self.sum -= self.values.pop_front().unwrap();
+        }
+        self.sum / self.values.len() as f64
+    }
+
+    fn group_delay(&self, _omega: f64) -> f64 {
+        (self.window - 1) as f64 / 2.0
+    }
+}
+
+struct Median {
+    window: usize,
+    values: VecDeque<f64>,
+}
+
+impl Filter for Median {
+    fn push(&mut self, x: f64) -> f64 {
+        self.values.push_back(x);
+        if self.values.len() > self.window {
+            self.values.pop_front();
+        }
+        let mut sorted: Vec<f64> = self.values.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        sorted[sorted.len() / 2]
+    }
+
+    // Not linear, but an edge comes through half a window late
+    fn group_delay(&self, _omega: f64) -> f64 {
+        (self.window - 1) as f64 / 2.0
+    }
+}
+
+// Polynomial least squares over the last `window` samples, evaluated at the window's centre
+struct SavitzkyGolay {
+    coefficients: Vec<f64>,
+    values: VecDeque<f64>,
+}
+
+impl SavitzkyGolay {
+    fn new(window: usize, poly_order: usize) -> Self {
+        // The centre's value is e₀ᵀ(JᵀJ)⁻¹Jᵀy with J[i][k] = zᵢᵏ, z running from -half to half
+        let half = (window / 2) as f64;
+        let powers = |z: f64| (0..=poly_order).map(move |k| z.powi(k as i32));
+        let mut m = vec![vec![0.0; poly_order + 2]; poly_order + 1];
+        for i in 0..window {
+            let z = i as f64 - half;
+            for (r, zr) in powers(z).enumerate() {
+                for (c, zc) in powers(z).enumerate() {
+                    m[r][c] += zr * zc;
+                }
+            }
+        }
+        m[0][poly_order + 1] = 1.0;
+        let x = solve_linear(m).expect("Savitzky-Golay normal equations are singular");
+        let coefficients = (0..window)
+            .map(|i| powers(i as f64 - half).zip(&x).map(|(p, x)| p * x).sum())
+            .collect();
+        Self { coefficients, values: VecDeque::with_capacity(window + 1) }
+    }
+}
+
+impl Filter for SavitzkyGolay {
+    fn push(&mut self, x: f64) -> f64 {
+        self.values.push_back(x);
+        if self.values.len() > self.coefficients.len() {
+            self.values.pop_front();
+        }
+        if self.values.len() < self.coefficients.len() {
+            return x;
+        }
+        self.values.iter().zip(&self.coefficients).map(|(v, c)| v * c).sum()
+    }
+
+    fn group_delay(&self, _omega: f64) -> f64 {
+        (self.coefficients.len() - 1) as f64 / 2.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain(stages: &str) -> FilterChain {
+        let config: FilterChainConfig = toml::from_str(&format!("channel = 0\nstages = {}", stages)).unwrap();
+        FilterChain::new(&config, 100.0)
+    }
+
+    // Amplitude of a sine at `freq_hz` after the chain has settled
+    fn gain(chain: &mut FilterChain, freq_hz: f64) -> f64 {
+        let out: Vec<f64> = (0..4000).map(|i| chain.push((2.0 * PI * freq_hz * i as f64 / 100.0).sin())).collect();
+        out[2000..].iter().fold(0f64, |m, v| m.max(v.abs()))
+    }
+
+    #[test]
+    fn filters_and_reports_delay() {
+        let lowpass = r#"[{ kind = "low_pass", cutoff_hz = 5.0, order = 4 }]"#;
+        assert!((gain(&mut chain(lowpass), 1.0) - 1.0).abs() < 0.01);
+        assert!((gain(&mut chain(lowpass), 5.0) - 0.5f64.sqrt()).abs() < 0.01);
+        assert!(gain(&mut chain(lowpass), 20.0) < 0.01);
+        assert!(gain(&mut chain(r#"[{ kind = "notch", freq_hz = 10.0, q = 5.0 }]"#), 10.0) < 0.01);
+        assert!(gain(&mut chain(r#"[{ kind = "band_pass", low_hz = 1.0, high_hz = 10.0, order = 3 }]"#), 0.1) < 0.01);
+
+        // A Savitzky-Golay filter reproduces its polynomial order exactly, half a window late
+        let mut sg = chain(r#"[{ kind = "savitzky_golay", window = 7, poly_order = 2 }]"#);
+        let out: Vec<f64> = (0..20).map(|i| sg.push((i * i) as f64)).collect();
+        assert!((out[19] - 16.0 * 16.0).abs() < 1e-6);
+        assert!((sg.group_delay_secs(1.0) - 0.03).abs() < 1e-12);
+
+        // A 2nd order low-pass delays slow signals by √2 / ω_c
+        let delay = chain(r#"[{ kind = "low_pass", cutoff_hz = 5.0 }]"#).group_delay_secs(0.01);
+        assert!((delay - 2f64.sqrt() / (2.0 * PI * 5.0)).abs() < 0.002, "{}", delay);
+        let mut median = chain(r#"[{ kind = "median", window = 3 }]"#);
+        assert_eq!([1.0, 2.0, 100.0, 3.0, 4.0].map(|x| median.push(x)), [1.0, 2.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn non_finite_samples_leave_the_state_alone() {
+        let mut chain = chain(r#"[{ kind = "low_pass", cutoff_hz = 5.0, order = 4 }, { kind = "moving_average", window = 5 }]"#);
+        let held = (0..500).map(|_| chain.push(1.0)).last().unwrap();
+        assert_eq!(chain.push(f64::NAN), held);
+        assert_eq!(chain.push(f64::INFINITY), held);
+        let out: Vec<f64> = (0..500).map(|_| chain.push(1.0)).collect();
+        assert!(out.iter().all(|v| v.is_finite()));
+        assert!((out[499] - 1.0).abs() < 1e-6);
+    }
+}
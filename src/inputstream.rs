@@ -1,101 +1,260 @@
+use std::fs::File;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::Sender;
+use std::time::{Instant, Duration};
+use csv::Writer;
+use tracing::{info, warn};
 
-/*
-trait DataInputStream {
-    fn audit(&mut self);
-    fn index(&mut self) -> Option<(f64, f64)>;
+use crate::channels::ChannelLayout;
+use crate::config::AcquisitionConfig;
+use crate::events::{EventKind, EventLog};
+use crate::processing::Processor;
+use crate::structs::RasaVariables;
+use crate::resample::WindowBuffer;
+use crate::streams::*;
+use crate::util::*;
+
+// A single reading from a data source. `values` holds every numeric column the source reports, in the order of
+// [[channels]]. `ttl` is the source's own interpretation of whether the TTL line is currently asserted
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub time: f64,
+    pub values: Vec<f64>,
+    pub ttl: bool,
 }
-*/
-struct SineInput {
-    pub deque: Arc<Mutex<VecDeque<(f64, f64)>>>,
-    index: usize,
+
+impl Sample {
+    pub fn new(time: f64, values: Vec<f64>, ttl: bool) -> Self {
+        Self { time, values, ttl }
+    }
+
+    pub fn channel(&self, ix: usize) -> f64 {
+        self.values.get(ix).copied().unwrap_or(0.0)
+    }
 }
 
-struct PhotometeryInput {
-    pub deque: Arc<Mutex<VecDeque<(f64, f64)>>>,
-    index: usize,
-    port: Box<dyn SerialPort>,
+// Anything that can produce samples. Implementors only need to know how to get the next reading out of their
+// hardware or simulator, the plotting, recording and analysis wiring is handled by `run_stream`
+pub trait InputStream: Send {
+    fn name(&self) -> &'static str;
+
+    // Blocks until the next sample is ready. Returning None ends the session's data stream
+    fn next_sample(&mut self) -> Option<Sample>;
 }
 
-impl SineInput {
-    fn new() -> Self {
-        SineInput {
-            deque: Arc::new(Mutex::new(VecDeque::new())),
-            index: 0,
-        }
+// The rig's TTL line is active low. Two consecutive low readings count as the TTL being received
+#[derive(Debug)]
+pub struct ActiveLowTtl {
+    last: i32,
+}
+
+impl ActiveLowTtl {
+    pub fn new() -> Self {
+        Self { last: 1 }
+    }
+
+    pub fn push(&mut self, reading: f64) -> bool {
+        let ttl = reading as i32 == 0 && self.last == 0;
+        self.last = reading as i32;
+        ttl
     }
 }
-/*
-impl PhotometeryInput {
-    fn new() -> Self {
-        PhotometeryInput {
-            deque: Arc::new(Mutex::new(VecDeque::new())),
-            index: 0,
-            port: PhotometeryInput::init_port()
-        }
+
+impl Default for ActiveLowTtl {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn init_port() -> Box<dyn SerialPort> {
-        let port = "COM3";
-        let baud_rate = 9600;
-        let ports = serialport::available_ports().expect("No ports found!");
-        let port = serialport::new(port, baud_rate)
-            .timeout(Duration::from_millis(10))
-            .open()
-            .expect("Failed to open port");
+// What the plots get for one sample: a value for each of the layout's sampled channels, see ChannelLayout
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub time: f64,
+    pub values: Vec<f64>,
+}
+
+// One-second rolling mean and standard deviation of both channels, shared with the stimulation rules
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelBaseline {
+    pub average: (f64, f64),
+    pub std: (f64, f64),
+}
 
-        port
+#[derive(Clone, Debug)]
+pub enum InputStreams {
+    TestStream,
+    PhotometryStream(String, String, bool),
+    OrnsteinStream,
+    InstantReplayStream(String)
+}
+
+impl InputStreams {
+    // Construct the source. Hardware is opened here, so call this from the thread that will drive it
+    pub fn open(&self, acquisition: &AcquisitionConfig, layout: &ChannelLayout) -> Box<dyn InputStream> {
+        match self {
+            InputStreams::TestStream => Box::new(teststream::TestStream::new()),
+            InputStreams::OrnsteinStream => Box::new(ornstein::OrnsteinStream::new()),
+            InputStreams::PhotometryStream(inport, _, _) => Box::new(photometry::PhotometryStream::open(inport, acquisition.baud_rate, layout.ttl)),
+            InputStreams::InstantReplayStream(file) => Box::new(instantreplay::InstantReplay::open(file, layout.inputs, layout.ttl)),
+        }
     }
 
-    pub fn audit(&mut self) {
-        self.index += 1;
-        let reader = std::io::BufReader::new(&mut self.port);
-
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    // Here you can parse the line as per your serialization format.
-                    // Assuming it's a string of integers separated by spaces:
-                    let numbers: Vec<i32> = line
-                        .split_whitespace()
-                        .filter_map(|num| num.parse::<i32>().ok())
-                        .collect();
-                    println!("{:?}", numbers);
-                    self.deque.lock().unwrap().push_back((self.index as f64 / 10.0, numbers[0] as f64));
-                    thread::sleep(Duration::from_millis(1));
-                }
-                Err(err) => {
-                    eprintln!("Error: {}", err);
-                    continue;
-                },
-            }
+    pub fn stim_port(&self) -> Option<&String> {
+        match self {
+            InputStreams::PhotometryStream(_, outport, true) => Some(outport),
+            _ => None,
         }
+    }
+}
+
+// How far the measured input rate may be from processing.sample_rate_hz before the filters count as mistuned
+pub const RATE_TOLERANCE: f64 = 0.05;
+// Seconds of data each rate measurement covers
+const RATE_CHECK_SECS: f64 = 10.0;
 
+// Measures the stream's sample rate against the rate the filters were designed for. A Butterworth, notch or
+// Savitzky-Golay stage run at another rate has its frequencies scaled by the ratio, and so does the reported delay
+pub struct RateCheck {
+    expected_hz: f64,
+    start: Option<f64>,
+    samples: usize,
+    mismatched: bool,
+}
 
-        //println!("At audit method {}", ix);
-        //thread::sleep(Duration::from_millis(1));
+impl RateCheck {
+    pub fn new(expected_hz: f64) -> Self {
+        Self { expected_hz, start: None, samples: 0, mismatched: false }
     }
 
-    fn index(&mut self) -> Option<(f64, f64)> {
-        println!("At index method");
-        self.deque.lock().unwrap().pop_front()
+    // The measured rate, once every RATE_CHECK_SECS of data. Warns when it leaves the tolerance and again when it
+    // is back within it
+    pub fn push(&mut self, time: f64) -> Option<f64> {
+        let start = match self.start {
+            Some(start) if time >= start => start,
+            // The first sample, or a replay starting over
+            _ => {
+                self.start = Some(time);
+                self.samples = 0;
+                return None;
+            }
+        };
+        self.samples += 1;
+        if time - start < RATE_CHECK_SECS {
+            return None;
+        }
+        let rate = self.samples as f64 / (time - start);
+        self.start = Some(time);
+        self.samples = 0;
+
+        let mismatched = (rate - self.expected_hz).abs() > RATE_TOLERANCE * self.expected_hz;
+        if mismatched && !self.mismatched {
+            warn!("Input runs at {:.1} Hz but the filters are designed for processing.sample_rate_hz = {} Hz, their cutoffs and delay are off by {:.0}%",
+                rate, self.expected_hz, (rate / self.expected_hz - 1.0) * 100.0);
+        } else if !mismatched && self.mismatched {
+            info!("Input rate back to {:.1} Hz, within {}% of processing.sample_rate_hz", rate, RATE_TOLERANCE * 100.0);
+        }
+        self.mismatched = mismatched;
+        Some(rate)
     }
 }
 
-impl SineInput {
-    pub fn audit(&mut self) {
-            self.index += 1;
-            let x = (self.index as f64 / 10.0);
-            let y = (self.index as f64 / 10.0).sin();
+// Everything downstream of an input source: the plot channel, the model window buffer, the recording, the TTL
+// flag, the rolling baselines and the event log, whose clock follows the stream. The processor filters and detrends
+// the layout's signal and isosbestic channels for everything but the recording, which keeps the raw inputs followed
+// by ΔF/F and the two trends when those are derived, and the plot while the GUI shows raw traces. With filters
+// configured the input rate is checked against the rate they were designed for
+pub struct StreamSinks {
+    pub tx: Sender<Frame>,
+    pub layout: Arc<ChannelLayout>,
+    pub window: Arc<Mutex<WindowBuffer>>,
+    pub processor: Processor,
+    pub writer: Writer<File>,
+    pub is_ttl: Arc<Mutex<bool>>,
+    pub baseline: Arc<Mutex<ChannelBaseline>>,
+    pub events: EventLog,
+    pub rate_check: Option<RateCheck>,
+}
+
+pub fn run_stream(mut stream: Box<dyn InputStream>, mut sinks: StreamSinks, vars: &Arc<RwLock<RasaVariables>>) {
+    info!("Beginning {} stream on active thread", stream.name());
+    let mut vec: Vec<Vec<f64>> = Vec::new();
+    let mut sec_start = Instant::now();
+    // Set from the first sample, a source that starts out asserted hasn't had a rising edge
+    let mut last_ttl: Option<bool> = None;
+    let layout = Arc::clone(&sinks.layout);
+
+    while let Some(sample) = stream.next_sample() {
+        let (window_secs, show_raw) = {
+            let vars = vars.read().unwrap();
+            (vars.window_secs, vars.show_raw)
+        };
+        let elapsed = sample.time;
+        let (raw0, raw1) = (sample.channel(layout.signal), sample.channel(layout.isosbestic));
+        let processed = sinks.processor.push(elapsed, raw0, raw1);
+        let (y0, y1, dff) = (processed.y0, processed.y1, processed.dff);
+        sinks.events.set_time(elapsed);
+        if let Some(rate) = sinks.rate_check.as_mut().and_then(|check| check.push(elapsed)) {
+            vars.write().unwrap().input_rate_hz = Some(rate);
+        }
+
+        if sample.ttl && !*sinks.is_ttl.lock().unwrap() {
+            *sinks.is_ttl.lock().unwrap() = true;
+            info!("Received TTL Signal.");
+        }
+        if last_ttl.map_or(false, |last| last != sample.ttl) {
+            let kind = if sample.ttl { EventKind::TtlRise } else { EventKind::TtlFall };
+            sinks.events.log(kind, String::new());
+        }
+        last_ttl = Some(sample.ttl);
+
+        // Raw inputs, then whichever derived channels the layout has. Missing columns read as 0
+        let mut values: Vec<f64> = (0..layout.inputs).map(|ix| sample.channel(ix)).collect();
+        values.extend(dff);
+        if let Some((trend0, trend1)) = processed.trend {
+            values.extend([trend0, trend1]);
+        }
+
+        let mut record = vec![elapsed.to_string(), unix_timestamp_ms().to_string()];
+        record.extend(values.iter().map(|v| v.to_string()));
+        sinks.writer.write_record(&record).expect("Could not write to CSV output");
 
-            self.deque.lock().unwrap().push_back((x,y));
-            //println!("At audit method {}", ix);
-            //thread::sleep(Duration::from_millis(1));
+        if !show_raw {
+            values[layout.signal] = y0;
+            values[layout.isosbestic] = y1;
         }
+        if sinks.tx.send(Frame { time: elapsed, values }).is_err() {
+            break;
+        }
+        sinks.window.lock().unwrap().push(elapsed, y0, y1, dff, window_secs);
+
+        // Rolling one-second baselines for the stimulation rules
+        vec.push(vec![y0, y1]);
+        if sec_start.elapsed() > Duration::from_secs(1) {
+            sec_start = Instant::now();
+            let v0 = vec.iter().filter_map(|v| v.get(0).copied()).collect::<Vec<_>>();
+            let v1 = vec.iter().filter_map(|v| v.get(1).copied()).collect::<Vec<_>>();
+            *sinks.baseline.lock().unwrap() = ChannelBaseline { average: average_vec(&vec), std: std_dev(&v0, &v1) };
+            vec.clear();
+        }
+    }
+    info!("{} stream finished", stream.name());
+}
 
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    fn index(&mut self) -> Option<(f64, f64)> {
-        //println!("At index method");
-        self.deque.lock().unwrap().pop_front()
+    #[test]
+    fn measures_the_input_rate() {
+        let mut check = RateCheck::new(100.0);
+        let rates: Vec<f64> = (0..2500).filter_map(|i| check.push(i as f64 / 80.0)).collect();
+        assert_eq!(rates.len(), 3);
+        assert!(rates.iter().all(|r| (r - 80.0).abs() < 1e-9), "{:?}", rates);
+        assert!(check.mismatched);
+        // A replay starting over begins a new measurement
+        assert_eq!(check.push(0.0), None);
+        let rates: Vec<f64> = (1..1001).filter_map(|i| check.push(i as f64 / 100.0)).collect();
+        assert!(rates.len() == 1 && (rates[0] - 100.0).abs() < 1e-9, "{:?}", rates);
+        assert!(!check.mismatched);
     }
 }
-*/
\ No newline at end of file
@@ -2,22 +2,39 @@ mod measurements;
 mod monitor;
 mod stim;
 mod util;
-mod threadedchannel;
 mod structs;
+mod inputstream;
+mod cli;
+mod config;
+mod templates;
+mod capture;
+mod analysis;
+mod model;
+mod detector;
+mod selector;
+mod safety;
+mod events;
+mod adaptive;
+mod calibration;
+mod rules;
+mod condition;
+mod processing;
+mod filters;
+mod resample;
+mod channels;
 
 use winit::window::Icon;
 use winit::window::WindowBuilder;
 use std::cmp::min;
-use std::collections::VecDeque;
 use std::rc::Rc;
 use std::sync::RwLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::{Path, PathBuf};
 use std::fs::{File, OpenOptions};
 use crate::monitor::MonitorApp;
-use crate::threadedchannel::deque_channel;
+use crate::resample::WindowBuffer;
 use crate::measurements::MeasurementWindow;
-use streams::ornstein::OrnsteinUhlenbeck;
+use crate::inputstream::{ChannelBaseline, InputStreams, RateCheck, StreamSinks};
 use crate::stim::*;
 use crate::util::*;
 use eframe::egui;
@@ -28,9 +45,23 @@ use std::sync::*;
 use serialport::*;
 use std::{sync, thread};
 use tracing::{debug, error, info, warn};
-use tch::{CModule, IndexOp, Kind, Tensor};
 use tracing::field::debug;
-//use clap::{Arg, App, SubCommand};
+use clap::Parser;
+use crate::cli::{Cli, Command};
+use crate::config::{ConditionConfig, ModelBackend, RasaConfig};
+use crate::templates::{load_templates, Template};
+use crate::detector::{build_detector, Detector, ShadowDetector};
+use crate::model::{Model, SharedModel};
+use crate::selector::ModelSelector;
+use crate::safety::{Interlock, SafetyPanel};
+use crate::events::{EventKind, EventLog};
+use crate::adaptive::AdaptiveThreshold;
+use crate::calibration::{Calibration, CalibrationPanel, SubjectProfile};
+use crate::rules::{RuleEngine, RuleInput};
+use crate::condition::{resolve_seed, ConditionRunner, AUTOMATIC};
+use crate::processing::Processor;
+use crate::capture::TemplateCapture;
+use crate::channels::ChannelLayout;
 use std::str::FromStr;
 
 
@@ -55,15 +86,6 @@ macro_rules! add_measurement {
     };
 }
 
-#[derive(Clone)]
-enum InputStreams {
-    TestStream,
-    PhotometryStream(String, String, bool),
-    OrnsteinStream,
-    InstantReplayStream(String)
-}
-
-
 fn config_subscriber() {
     let subscriber = tracing_subscriber::FmtSubscriber::builder()
         .with_max_level(tracing::Level::TRACE)
@@ -71,39 +93,318 @@ fn config_subscriber() {
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 }
 
-fn get_fpath() -> (String, String) {
-    let mut file_number = 0;
-    let mut file_path = format!("data/data{}.csv", file_number);
-    let mut reward_path = format!("data/reward{}.csv", file_number);
+// All the files of one session share the number of its data<N>.csv
+struct SessionPaths {
+    data: PathBuf,
+    reward: PathBuf,
+    shadow: PathBuf,
+    stim: PathBuf,
+    events: PathBuf,
+    config: PathBuf,
+    // Blinded: the experimental condition and what it did
+    condition: PathBuf,
+    condition_log: PathBuf,
+}
 
-    while Path::new(&file_path).exists() {
+fn get_fpath(dir: &Path) -> SessionPaths {
+    let mut file_number = 0;
+    while dir.join(format!("data{}.csv", file_number)).exists() {
         file_number += 1;
-        file_path = format!("data/data{}.csv", file_number);
-        reward_path = format!("data/reward{}.csv", file_number);
     }
 
-    (file_path, reward_path)
+    SessionPaths {
+        data: dir.join(format!("data{}.csv", file_number)),
+        reward: dir.join(format!("reward{}.csv", file_number)),
+        shadow: dir.join(format!("shadow{}.csv", file_number)),
+        stim: dir.join(format!("stim{}.csv", file_number)),
+        events: dir.join(format!("events{}.csv", file_number)),
+        config: dir.join(format!("config{}.toml", file_number)),
+        condition: dir.join(format!("condition{}.toml", file_number)),
+        condition_log: dir.join(format!("condition{}.csv", file_number)),
+    }
+}
+
+// Config file, then the subject's calibrated profile, then command line overrides and the condition file, then
+// validation. A missing rasa.toml just means defaults
+fn load_config(cli: &Cli) -> RasaConfig {
+    let mut config = if cli.session.config.exists() {
+        match RasaConfig::load(&cli.session.config) {
+            Ok(c) => { info!("Loaded session config {:?}", cli.session.config); c },
+            Err(e) => { error!("{}", e); std::process::exit(1) }
+        }
+    } else {
+        warn!("Config {:?} not found, using defaults", cli.session.config);
+        RasaConfig::default()
+    };
+    if let Some(subject) = &cli.session.subject {
+        config.session.subject = subject.clone();
+    }
+    if let Some(path) = SubjectProfile::path_for(&config).filter(|p| p.exists()) {
+        match SubjectProfile::load(&path) {
+            Ok(profile) => { info!("Loaded subject profile {:?}", path); profile.apply(&mut config) },
+            Err(e) => { error!("{}", e); std::process::exit(1) }
+        }
+    }
+    cli.session.apply(&mut config);
+    // Not logged, the condition file is what keeps the session blind
+    if let Some(path) = &cli.session.condition {
+        match ConditionConfig::load(path) {
+            Ok(condition) => config.condition = condition,
+            Err(e) => { error!("{}", e); std::process::exit(1) }
+        }
+    }
+
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        std::process::exit(1)
+    }
+    config
+}
+
+// The model is only required when the detector uses it, so the classical detectors run without libtorch
+fn load_model_for(config: &RasaConfig) -> Option<Arc<Model>> {
+    match Model::load(&config.model.path, config.model.backend) {
+        Ok(m) => { info!("Loaded model {:?} successfully ({} x {} in, {} out)", m.path, m.meta.channels.len(), m.meta.input_len, m.meta.output_dim); Some(Arc::new(m)) },
+        Err(e) if config.detection.detector.uses_model() => { error!("{}. Aborting...", e); std::process::exit(1) },
+        Err(e) => { warn!("{}. Template capture is disabled until a model is loaded", e); None }
+    }
+}
+
+fn build_detector_or_exit(config: &RasaConfig, model: &SharedModel, templates: &Arc<RwLock<Vec<Template>>>) -> Box<dyn Detector> {
+    match build_detector(&config.detection.detector, model, templates) {
+        Ok(d) => { info!("Using {} detector with threshold {}", d.name(), d.threshold()); d },
+        Err(e) => { error!("Could not build detector: {}", e); std::process::exit(1) }
+    }
+}
+
+// Templates from `paths`, or the model's default template when there are none. Anything that doesn't match
+// the model's window length or output size is refused here rather than at the first window
+fn load_templates_or_exit(paths: &[PathBuf], model: Option<&Arc<Model>>, window_len: usize, required: bool) -> Vec<Template> {
+    let mut paths = paths.to_vec();
+    if paths.is_empty() {
+        paths.extend(model.and_then(|m| m.meta.default_template.clone()));
+    }
+    if paths.is_empty() && required {
+        error!("No templates configured and the model has no default template. Aborting...");
+        std::process::exit(1)
+    }
+
+    let templates = match load_templates(&paths) {
+        Ok(templates) => templates,
+        Err(e) => { error!("{}", e); std::process::exit(1) }
+    };
+    for t in &templates {
+        info!("Loaded template '{}' ({} values)", t.name, t.vector.len());
+    }
+
+    if let Some(model) = model {
+        if let Err(e) = model.check_session(window_len, &templates) {
+            error!("{}. Aborting...", e);
+            std::process::exit(1)
+        }
+    }
+    templates
+}
+
+// Shadow detectors share the session model and templates unless they name their own
+fn build_shadows_or_exit(config: &RasaConfig, model: &SharedModel, templates: &Arc<RwLock<Vec<Template>>>) -> Vec<ShadowDetector> {
+    config.detection.shadow.iter().map(|shadow| {
+        let uses_model = shadow.detector.uses_model();
+        let (model, templates) = match &shadow.model {
+            Some(path) if uses_model => {
+                let candidate = match Model::load(path, config.model.backend) {
+                    Ok(m) => Arc::new(m),
+                    Err(e) => { error!("Shadow '{}': {}. Aborting...", shadow.name, e); std::process::exit(1) }
+                };
+                let t = load_templates_or_exit(&shadow.templates, Some(&candidate), config.acquisition.window_len, true);
+                (Arc::new(RwLock::new(Some(candidate))), Arc::new(RwLock::new(t)))
+            }
+            _ if uses_model && !shadow.templates.is_empty() => {
+                let session_model = model.read().unwrap().clone();
+                let t = load_templates_or_exit(&shadow.templates, session_model.as_ref(), config.acquisition.window_len, true);
+                (Arc::clone(model), Arc::new(RwLock::new(t)))
+            }
+            _ => (Arc::clone(model), Arc::clone(templates)),
+        };
+
+        let detector = match build_detector(&shadow.detector, &model, &templates) {
+            Ok(d) => d,
+            Err(e) => { error!("Could not build shadow detector '{}': {}", shadow.name, e); std::process::exit(1) }
+        };
+        info!("Shadow detector '{}' ({}) with threshold {}", shadow.name, detector.name(), detector.threshold());
+        ShadowDetector::new(shadow.name.clone(), detector)
+    }).collect()
 }
 
+// Deliver one stimulation if the safety limits allow it. Arming, cooldown and TTL are up to the caller
+fn stimulate(interlock: &mut Interlock, device: Option<&mut StimDevice>, events: &EventLog, cause: &str) -> bool {
+    match interlock.check(Instant::now()) {
+        Ok(()) => {
+            info!("Stimulation delivered ({})", cause);
+            events.log(EventKind::StimDelivered, cause);
+            interlock.record(Instant::now());
+            if let Some(device) = device {
+                if let Err(e) = device.deliver() {
+                    error!("Stimulation failed: {}", e);
+                }
+            }
+            true
+        }
+        Err(reason) => {
+            warn!("Stimulation blocked ({}): {}", cause, reason);
+            events.log(EventKind::StimBlocked, format!("{}: {}", cause, reason));
+            false
+        }
+    }
+}
 
 fn main() {
     config_subscriber();
+    let cli = Cli::parse();
+    let config = load_config(&cli);
+
+    match &cli.command {
+        Command::Analyze { file, output } => {
+            std::fs::create_dir_all(&config.session.output_dir).expect("Could not create output directory");
+            let output = output.clone().unwrap_or(get_fpath(&config.session.output_dir).reward);
+            let umodel = load_model_for(&config);
+            let templates = Arc::new(RwLock::new(load_templates_or_exit(&config.model.templates, umodel.as_ref(), config.acquisition.window_len, config.detection.detector.uses_model())));
+            let mut detector = build_detector_or_exit(&config, &Arc::new(RwLock::new(umodel)), &templates);
+            if let Err(e) = analysis::analyze_recording(detector.as_mut(), file, &output, &config) {
+                error!("Analysis of {:?} failed: {}", file, e);
+            }
+        }
+        Command::CompareBackends { file, reference, candidate, tolerance } => {
+            let load = |path: &PathBuf| match Model::load(path, ModelBackend::Auto)
+                .and_then(|m| m.check_session(config.acquisition.window_len, &[]).map(|_| m)) {
+                Ok(m) => m,
+                Err(e) => { error!("{}", e); std::process::exit(1) }
+            };
+            let (reference, candidate) = (load(reference), load(candidate));
+            match analysis::compare_backends(&reference, &candidate, file, &config) {
+                Ok(c) if c.max_abs_diff <= *tolerance => {
+                    info!("Backends agree over {} windows: max difference {:e}, mean {:e}", c.windows, c.max_abs_diff, c.mean_abs_diff);
+                }
+                Ok(c) => {
+                    error!("Backends disagree over {} windows: max difference {:e} exceeds {:e} (mean {:e})",
+                        c.windows, c.max_abs_diff, tolerance, c.mean_abs_diff);
+                    std::process::exit(1)
+                }
+                Err(e) => { error!("Comparison on {:?} failed: {}", file, e); std::process::exit(1) }
+            }
+        }
+        command => {
+            let active_thread = command.input_stream().expect("Subcommand has no input stream");
+            run_session(active_thread, config);
+        }
+    }
+}
 
-    // Get next available filepath in pattern {data/data<num>.csv}
-    let (file_path , reward_path) = get_fpath();
+fn run_session(active_thread: InputStreams, config: RasaConfig) {
+    std::fs::create_dir_all(&config.session.output_dir).expect("Could not create output directory");
+    let cooldown = Duration::from_secs(config.detection.cooldown_secs);
+
+    // Get next available filepath in pattern {<output_dir>/data<num>.csv}
+    let paths = get_fpath(&config.session.output_dir);
+    if let Err(e) = config.save(&paths.config) {
+        error!("{}", e);
+    }
+    let mut condition_config = config.condition.clone();
+    resolve_seed(&mut condition_config);
+    if let Err(e) = condition_config.save(&paths.condition) {
+        error!("{}. Aborting...", e);
+        std::process::exit(1)
+    }
+    let condition_log = Writer::from_writer(File::create(&paths.condition_log).expect("Could not create the condition log"));
+    let mut condition = ConditionRunner::new(&condition_config, condition_log);
+    let condition_label = condition_config.label.clone();
+    info!("Condition '{}' recorded blind to {:?}", condition_label, paths.condition);
     let is_ttl = Arc::new(Mutex::new(false));
     let ttl_clone = is_ttl.clone();
+    let events = match EventLog::open(&paths.events) {
+        Ok(events) => { info!("Logging session events to {:?}", paths.events); events },
+        Err(e) => { error!("Could not create {:?}: {}", paths.events, e); std::process::exit(1) }
+    };
+    let ai_events = events.clone();
+    events.log(EventKind::ParameterChange, format!("condition: {}", condition_label));
+
+    let layout = Arc::new(ChannelLayout::new(&config));
+    info!("Channels: {}", layout.channels.iter().map(|c| c.header()).collect::<Vec<_>>().join(", "));
+    let processor = Processor::new(&config.processing);
+    let filter_delays = processor.filter_delays(config.processing.delay_at_hz);
+    if let Some((delay0, delay1)) = filter_delays {
+        info!("Filters delay channel 0 by {:.1} ms and channel 1 by {:.1} ms at {} Hz", delay0 * 1000.0, delay1 * 1000.0, config.processing.delay_at_hz);
+    }
 
     let program_vars = Arc::new(RwLock::new(structs::RasaVariables {
-        show_box: true,
-
-        look_behind: 4,
-        skip: 30,
-        channels:5,
+        show_box: config.display.show_box,
+
+        look_behind: config.display.look_behind,
+        window_secs: config.acquisition.window_secs,
+        dff: config.processing.dff,
+        processed: config.processing.alters_channels(),
+        show_raw: false,
+        filter_delay_ms: filter_delays.map(|(d0, d1)| (d0 * 1000.0, d1 * 1000.0)),
+        sample_rate_hz: config.processing.sample_rate_hz,
+        input_rate_hz: None,
+        threshold: config.detection.detector.threshold(),
+        cooldown_secs: config.detection.cooldown_secs,
+        min_cooldown_secs: config.stimulation.protocol_secs().ceil() as u64,
+        sigma: None,
+        adaptive_threshold: None,
     }));
+    let ai_vars = Arc::clone(&program_vars);
+
+    // Shared with the GUI so templates captured from the plot and models picked in the sidebar are used straight away
+    let umodel = load_model_for(&config);
+    let templates = Arc::new(RwLock::new(load_templates_or_exit(&config.model.templates, umodel.as_ref(), config.acquisition.window_len, config.detection.detector.uses_model())));
+    let shared_model: SharedModel = Arc::new(RwLock::new(umodel));
+    let mut detector = build_detector_or_exit(&config, &shared_model, &templates);
+    let mut shadows = build_shadows_or_exit(&config, &shared_model, &templates);
+    // Every processed sample goes in, the analysis thread takes the latest window_secs resampled to window_len.
+    // Template capture can take anything still on the plot
+    let window_buffer = Arc::new(Mutex::new(WindowBuffer::new(config.acquisition.window_len, monitor::MAX_LOOK_BEHIND as f64)));
+    let capture = TemplateCapture::new(
+        Arc::clone(&shared_model),
+        Arc::clone(&templates),
+        config.model.template_dir.clone(),
+        events.clone(),
+        Arc::clone(&window_buffer),
+        Arc::clone(&program_vars),
+    );
+    let selector = ModelSelector::new(
+        Arc::clone(&shared_model),
+        Arc::clone(&templates),
+        config.model.backend,
+        config.acquisition.window_len,
+        config.model.path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        events.clone(),
+    );
+
+    // The stimulator gets the protocol before acquisition starts, a device that refuses it ends the session
+    let mut stim_device: Option<StimDevice> = active_thread.stim_port().map(|outport| {
+        let log = Writer::from_writer(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(true)
+                .open(&paths.stim)
+                .unwrap()
+        );
+        match StimDevice::open(outport, config.acquisition.baud_rate, &config.stimulation, log) {
+            Ok(device) => { info!("Logging pulse trains to {:?}", paths.stim); device },
+            Err(e) => { error!("{}. Aborting...", e); std::process::exit(1) }
+        }
+    });
+    let interlock = Arc::new(Mutex::new(Interlock::new(config.safety.clone(), config.stimulation.stim_secs(), cooldown)));
+    let stim_stop = stim_device.as_ref().map(|d| d.stop_handle().expect("Could not open a second handle on the stimulator"));
+    let ai_interlock = Arc::clone(&interlock);
+    let calibration = Arc::new(Mutex::new(Calibration::new()));
+    let ai_calibration = Arc::clone(&calibration);
+    let calibration_panel = CalibrationPanel::new(calibration, Arc::clone(&program_vars), Arc::clone(&shared_model), events.clone(), &config);
 
     //println!("Got here");
-    let mut vis_app = monitor::MonitorApp::new(&program_vars);
+    let mut vis_app = monitor::MonitorApp::new(&program_vars, Arc::clone(&layout), Some(capture), Some(selector), SafetyPanel::new(interlock, stim_stop, condition_label.clone(), events.clone()), calibration_panel, events.clone());
     //println!("Got here");
     //let mut reward_app = MonitorApp::new(10, 1);
     let native_options = eframe::NativeOptions::default();
@@ -117,86 +418,130 @@ fn main() {
     let ports = available_ports().expect("No ports found!");
     info!("{:?}", ports);
 
-    let umodel : CModule;
-    match CModule::load("models/nested_model4.pt") {
-        Ok(m) => {info!("Loaded torch model successfully"); umodel = m},
-        Err(m) => {error!("Unable to load torch model. Aborting..."); panic!() }
-    }
-
-    // Data read/write channel
-    let active_thread =
-        //InputStreams::PhotometryStream(String::from("COM3"), String::from("COM4"), false);
-        InputStreams::InstantReplayStream(String::from("data/data85.csv"));
-    let active_clone = active_thread.clone();
-
     let mut writer: Writer<File> = Writer::from_writer(
             OpenOptions::new()
                 .write(true)
                 .create(true)
                 .append(true)
-                .open(file_path)
+                .open(&paths.data)
                 .unwrap()
         );
+    writer.write_record(layout.header()).expect("Could not write to CSV output");
 
-    info!("{:?}", reward_path);
+    info!("Recording to {:?} and {:?}", paths.data, paths.reward);
 
     let mut r_writer: Writer<File> = Writer::from_writer(
         OpenOptions::new()
             .write(true)
             .create(true)
             .append(true)
-            .open(reward_path)
+            .open(&paths.reward)
             .unwrap()
     );
 
+    // Shadow detectors get their own file so the primary's reward CSV keeps its format
+    let mut s_writer: Option<Writer<File>> = if shadows.is_empty() {
+        None
+    } else {
+        info!("Recording shadow detectors to {:?}", paths.shadow);
+        Some(Writer::from_writer(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(true)
+                .open(&paths.shadow)
+                .unwrap()
+        ))
+    };
+
     let (tx, rx) = mpsc::channel();
     let (tx_reward, rx_reward) = mpsc::channel();
-    // Custom VecDeque channels. Can be read from and written to without explicit locking
-    // Have size of 64. Designed so that when an element is added, fanother is popped. Pretty cool
-    let (tx_deque0, rx_deque0) = deque_channel(64);
-    let (tx_deque1, rx_deque1) = deque_channel(64);
-    let (tx_time, rx_time) = deque_channel(64);
+    let ai_window_buffer = Arc::clone(&window_buffer);
+    let adaptive_config = config.detection.adaptive.clone();
+    let mut rules = RuleEngine::new(config.detection.combine_rules, &config.detection.rules);
+    let baseline = Arc::new(Mutex::new(ChannelBaseline::default()));
+    let ai_baseline = Arc::clone(&baseline);
+    if adaptive_config.enabled {
+        info!("Adaptive threshold: stimulating above a z-score of {} (up to {}) over the last {} scores",
+            adaptive_config.base_sigma, adaptive_config.max_sigma, adaptive_config.history);
+    }
 
 
 
     thread::spawn(move || {
-        let mut writeport: Option<Box<dyn SerialPort>> = None;
-        if let InputStreams::PhotometryStream(_, outport, true) = active_clone {
-            writeport = Some(serialport::new(outport, 115200)
-                .timeout(Duration::from_millis(10))
-                .open()
-                .expect("Failed to administer stimulation to input port"));
-        }
 
-        let mut zapper_timer = Instant::now();
         let mut ix: usize = 0;
-        let mut sigma = 3.7;
-        let max_sigma = 5.5;
-        let sigma_inc = 0.05;
+        // Blocks by cooldown are recorded once per cooldown, not for every window that fires during it. Blocks by
+        // the rules once per run of windows the detector fires on
+        let mut cooldown_logged = false;
+        let mut rules_logged = false;
+        let mut adaptive = AdaptiveThreshold::new(adaptive_config);
+        // The same window is scored until the next sample arrives, so a failing detector is only reported when
+        // its error changes, or comes back after it has worked again
+        let mut last_error: Option<String> = None;
+        let mut shadow_errors: Vec<Option<String>> = vec![None; shadows.len()];
 
-        let max_size = 1024;
-        let mut vec_deque: VecDeque<f64> = VecDeque::with_capacity(max_size);
-        for i in 1..=max_size+5{
-            vec_deque.push_back(i as f64);
+        loop {
+            // Threshold, cooldown and window can be changed from the sidebar at any time
+            let (threshold, cooldown_secs, window_secs) = {
+                let vars = ai_vars.read().unwrap();
+                (vars.threshold, vars.cooldown_secs, vars.window_secs)
+            };
+            if threshold != detector.threshold() {
+                detector.set_threshold(threshold);
+            }
 
-            if vec_deque.len() > max_size {
-                let popped_element = vec_deque.pop_front();
+            // Manual stimulations from the sidebar skip arming and TTL, but not the cooldown or the safety limits
+            {
+                let mut interlock = ai_interlock.lock().unwrap();
+                interlock.set_cooldown(Duration::from_secs(cooldown_secs));
+                if interlock.take_manual() {
+                    if ai_calibration.lock().unwrap().is_recording() {
+                        warn!("Stimulation blocked (manual): calibration baseline is recording");
+                        ai_events.log(EventKind::StimBlocked, "manual: calibrating");
+                    }
+                    else if interlock.start_cooldown(Instant::now()) {
+                        cooldown_logged = false;
+                        if stimulate(&mut interlock, stim_device.as_mut(), &ai_events, "manual") {
+                            adaptive.on_stimulation(Instant::now());
+                        }
+                    } else {
+                        let remaining = interlock.cooldown_remaining(Instant::now()).as_secs_f64();
+                        warn!("Stimulation blocked (manual): {:.1} s of cooldown remaining", remaining);
+                        ai_events.log(EventKind::StimBlocked, format!("manual: cooldown, {:.1} s remaining", remaining));
+                    }
+                }
             }
-        }
 
-        let base_tens = Tensor::of_slice(&[ 0.12634977, -0.06141152, -0.04964269,  0.07968105, -0.04368583,
-            0.13546535,  0.07548738, -0.03056486,  0.06184907,  0.07327018,
-            0.01710543,  0.10000183,  0.0804975 , -0.01080727,  0.1048333 ,
-            0.12170962, -0.02461481, -0.05775008, -0.01931712, -0.07454138,
-            -0.04416006,  0.13611916, -0.07255794,  0.09354435,  0.02245703,
-            0.05057328, -0.09178815,  0.06281088, -0.03156299, -0.03452384,
-            -0.04695314,  0.17657361]);
-        //let weight = tens2.dist(&tens2).double_value(&[]);
+            // Open loop and matched sham stimulations. Like manual ones they skip the detector, rules and TTL but
+            // not the cooldown or the safety limits. They are logged like closed loop ones, and why one wasn't
+            // delivered only goes to condition<N>.csv, so the event log doesn't give the protocol away
+            let now_data = ai_events.time();
+            if condition.due(now_data) {
+                let calibrating = ai_calibration.lock().unwrap().is_recording();
+                let mut interlock = ai_interlock.lock().unwrap();
+                let (delivered, detail) = if calibrating {
+                    (false, String::from("calibrating"))
+                } else if !interlock.armed {
+                    (false, String::from("disarmed"))
+                } else if interlock.start_cooldown(Instant::now()) {
+                    cooldown_logged = false;
+                    (stimulate(&mut interlock, stim_device.as_mut(), &ai_events, AUTOMATIC), String::new())
+                } else {
+                    (false, format!("cooldown, {:.1} s remaining", interlock.cooldown_remaining(Instant::now()).as_secs_f64()))
+                };
+                condition.delivered(now_data, delivered, &detail);
+                if delivered {
+                    adaptive.on_stimulation(Instant::now());
+                }
+            }
 
-        loop {
-            let v0: Vec<f64> = rx_deque0.deque.lock().unwrap().clone().into_iter().map(|value| value as f64).collect();
-            let v1: Vec<f64> = rx_deque1.deque.lock().unwrap().clone().into_iter().map(|value| value as f64).collect();
-            let v2: Vec<f64> = rx_time.deque.lock().unwrap().clone().into_iter().map(|value| value as f64).collect();
+            let resampled = ai_window_buffer.lock().unwrap().window(window_secs);
+            let resampled = match resampled {
+                Some(resampled) => resampled,
+                None => continue,
+            };
+            let (v1, v2) = (&resampled.v1, &resampled.times);
 
 
             let min_val = v1.clone().into_iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0);
@@ -220,72 +565,133 @@ fn main() {
                 }
             }
 
+            let window = resampled.as_window();
+            match detector.detect(&window) {
 
-            let (mut input_vec, nv1) = normalize_array(&v0, &v1);
-            input_vec.extend(nv1);
-            let input_data = Tensor::of_slice(&input_vec).unsqueeze(0).unsqueeze(2).to_kind(Kind::Float);
-            //input_data.print();
-
-            //println!("{:?}
-
-            match umodel.forward_ts(&[input_data]) {
-
-                Ok(output_data) => {
-                    // The forward method was successful and returned a Tensor
-                    //println!("Output data: ");
-                    //let tens1: Tensor = output_data.mean_dim(1, false, Kind::Float);
-                    //tens1.print();
-                    // PEAK DETECTION TENSOR
-                    let tens2 = Tensor::of_slice(&[-0.1309, -0.0426, -0.0295,  0.1515,  0.1200, -0.3180,  0.1198,  0.0594]);
-                    let tens32 = Tensor::of_slice(&[-0.0007,  0.1063, -0.0803,  0.0755, -0.0697, -0.1071,  0.2100, -0.0241,
-                        -0.1550,  0.0149,  0.0137,  0.0195, -0.0449, -0.0128, -0.0764, -0.0304,
-                        0.0700,  0.0375,  0.0911,  0.2336,  0.0950,  0.0468, -0.0787,  0.0491,
-                        0.1544, -0.1593, -0.0150,  0.1328,  0.0511,  0.0159, -0.0860, -0.0134]);
-                    let distance = output_data.dist(&tens32);
-                    //println!("{:?}", output_data.size());
-                    let distance_scalar = distance.double_value(&[]);
-                    vec_deque.push_back(distance_scalar);
-                    vec_deque.pop_front();
+                Ok(detection) => {
+                    last_error = None;
+                    let distance_scalar = detection.score;
+                    let template_name = &detection.label;
                     //tens1.print();
                     //debug!("Distance: {}", distance_scalar);
                     r_writer
                         .write_record(&[
                             min_time.unwrap_or(&0.0).to_string(),
                             max_time.unwrap_or(&0.0).to_string(),
-                            distance_scalar.to_string()
+                            distance_scalar.to_string(),
+                            template_name.clone(),
                         ]).expect("Could not write to CSV output");
 
-                    let stddev = std_dev_vec_deque(&vec_deque).unwrap();
-                    let average = average_vec_deque(&vec_deque).unwrap();
-                    let zscore = (distance_scalar - average) / stddev;
+                    // No stimulation while the calibration baseline is recorded
+                    let calibrating = ai_calibration.lock().unwrap().record(*max_time.unwrap_or(&0.0), distance_scalar);
+
+                    let now = Instant::now();
+                    let zscore = adaptive.push(*max_time.unwrap_or(&0.0), distance_scalar);
+                    let sigma = adaptive.sigma(now);
+                    let fire = if adaptive.enabled() {
+                        zscore.map_or(false, |z| z > sigma)
+                    } else {
+                        detection.fire
+                    };
+                    if adaptive.enabled() {
+                        let score_threshold = adaptive.score_threshold(now);
+                        let mut vars = ai_vars.write().unwrap();
+                        vars.sigma = Some(sigma);
+                        vars.adaptive_threshold = score_threshold;
+                    }
+                    let zscore = zscore.unwrap_or(f64::NAN);
                     let avg_time = max_time.unwrap_or(&0.0) / 1.0;
                     tx_reward.send((avg_time, distance_scalar));
 
-                    if distance_scalar > 300.0 {
-                        if zapper_timer.elapsed() > Duration::from_secs(16) {
-                            zapper_timer = Instant::now();
-                            if sigma < max_sigma {
-                                sigma += sigma_inc;
-                            }
-                            if *ttl_clone.lock().unwrap() {
-                                info!("Stimulation received after peak with reward {} and z-score {}", distance_scalar, zscore);
-                                if let Some(mut w) = writeport.as_mut() {
-                                    w.write(&['s' as u8]);
-                                }
+                    let outcome = rules.evaluate(&RuleInput {
+                        time: *max_time.unwrap_or(&0.0),
+                        window: &window,
+                        detection: &detection,
+                        fired: fire,
+                        ttl: *ttl_clone.lock().unwrap(),
+                        baseline: *ai_baseline.lock().unwrap(),
+                    });
+                    if !fire {
+                        rules_logged = false;
+                    }
 
-                            }
-                            else {
-                                warn!("Stimulation cannot be administered. TTL bit not received.")
+                    if outcome.pass && calibrating {
+                        debug!("Calibrating - received '{}' reward {} and z-score {}", template_name, distance_scalar, zscore);
+                    }
+                    else if outcome.pass {
+                        let mut interlock = ai_interlock.lock().unwrap();
+                        if !interlock.armed {
+                            debug!("Disarmed - received '{}' reward {} and z-score {}", template_name, distance_scalar, zscore);
+                        }
+                        else if interlock.cooldown_remaining(Instant::now()) == Duration::ZERO {
+                            // The decision goes to condition<N>.csv. Sham and open loop stop there, so only a
+                            // stimulation starts the cooldown
+                            let detail = format!("after '{}' with reward {} and z-score {} (sigma {:.2}), rules passed: {}",
+                                template_name, distance_scalar, zscore, sigma, outcome.passed.join(", "));
+                            let time = *max_time.unwrap_or(&0.0);
+                            if condition.decide(time, cooldown_secs as f64, &detail) {
+                                interlock.start_cooldown(Instant::now());
+                                cooldown_logged = false;
+                                let delivered = stimulate(&mut interlock, stim_device.as_mut(), &ai_events, AUTOMATIC);
+                                condition.delivered(time, delivered, "");
+                                if delivered {
+                                    adaptive.on_stimulation(Instant::now());
+                                }
                             }
                         }
                         else {
-                            info!("Cooldown - received reward {} and z-score {}", distance_scalar, zscore);
+                            info!("Cooldown - received '{}' reward {} and z-score {}", template_name, distance_scalar, zscore);
+                            if !cooldown_logged {
+                                cooldown_logged = true;
+                                ai_events.log(EventKind::StimBlocked, format!("after '{}' with reward {}: cooldown", template_name, distance_scalar));
+                            }
                         }
                     }
+                    else if fire && !calibrating && !rules_logged {
+                        rules_logged = true;
+                        warn!("Stimulation rules not met: {}", outcome.failed.join(", "));
+                        ai_events.log(EventKind::StimBlocked, format!("after '{}' with reward {}: rules not met: {}",
+                            template_name, distance_scalar, outcome.failed.join(", ")));
+                    }
                 }
                 Err(e) => {
-                    // The forward method failed and returned a TchError
-                    error!("Error: {:?}", e);
+                    // The model failed or the window doesn't suit the detector
+                    let e = e.to_string();
+                    if last_error.as_ref() != Some(&e) {
+                        error!("Error: {}", e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+
+            // Shadows only ever write to their CSV: name, window, score, label, fired, would have stimulated
+            let ttl = *ttl_clone.lock().unwrap();
+            for (shadow, last_error) in shadows.iter_mut().zip(shadow_errors.iter_mut()) {
+                match shadow.evaluate(&window, ttl, Duration::from_secs(cooldown_secs)) {
+                    Ok((detection, would_stimulate)) => {
+                        *last_error = None;
+                        if would_stimulate {
+                            debug!("Shadow '{}' would have stimulated on '{}' with score {}", shadow.name, detection.label, detection.score);
+                        }
+                        if let Some(w) = s_writer.as_mut() {
+                            w.write_record(&[
+                                shadow.name.clone(),
+                                min_time.unwrap_or(&0.0).to_string(),
+                                max_time.unwrap_or(&0.0).to_string(),
+                                detection.score.to_string(),
+                                detection.label,
+                                detection.fire.to_string(),
+                                would_stimulate.to_string(),
+                            ]).expect("Could not write to CSV output");
+                        }
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        if last_error.as_ref() != Some(&e) {
+                            error!("Shadow '{}' ({}): {}", shadow.name, shadow.detector_name(), e);
+                            *last_error = Some(e);
+                        }
+                    }
                 }
             }
             //thread::sleep(Duration::from_millis(10));
@@ -294,29 +700,14 @@ fn main() {
         }
     });
 
-    match active_thread.clone() {
-        InputStreams::InstantReplayStream(file) => {
-            thread::spawn(move || {
-                streams::instantreplay::start_instant_replay(file, tx, &tx_deque0, &tx_deque1, &tx_time, writer, &program_vars);
-            });
-        }
-        InputStreams::TestStream => { // Stream for Sin + spike data
-            thread::spawn(move || {
-                streams::teststream::start_test_stream(tx);
-            });
-        }
-        InputStreams::OrnsteinStream => {
-            thread::spawn(move || {
-                streams::ornstein::start_ornstein_stream(tx, &tx_deque0, &tx_deque1, &tx_time, writer, is_ttl);
-            });
-        }
-        InputStreams::PhotometryStream(inport, outport, useout) => {
-            thread::spawn(move || {
-                // TODO: Make sure is_ttl is working
-                streams::photometry::start_photometry_stream(&inport, tx, &tx_deque0, &tx_deque1, &tx_time, writer, is_ttl, &program_vars);
-            });
-        }
-    }
+    // Every source goes through the same wiring, see inputstream::run_stream
+    let rate_check = filter_delays.map(|_| RateCheck::new(config.processing.sample_rate_hz));
+    let sinks = StreamSinks { tx, layout: Arc::clone(&layout), window: window_buffer, processor, writer, is_ttl, baseline, events, rate_check };
+    let stream_layout = Arc::clone(&layout);
+    thread::spawn(move || {
+        let stream = active_thread.open(&config.acquisition, &stream_layout);
+        inputstream::run_stream(stream, sinks, &program_vars);
+    });
 
     let reader = thread::spawn(move || {
         loop {
@@ -332,16 +723,15 @@ fn main() {
                 last_reward = Some(val);
             }
 
-            if let Some(val) = last_received {
+            if let Some(frame) = last_received {
                 // Handle the received value
-                add_measurement!(*vis_monitor, val[0], 0);
-                add_measurement!(*vis_monitor, val[1], 1);
-                add_measurement!(*vis_monitor, val[2], 2);
-                add_measurement!(*vis_monitor, val[3], 3);
+                for (ix, value) in frame.values.iter().enumerate() {
+                    add_measurement!(*vis_monitor, (frame.time, *value), ix);
+                }
             }
 
             if let Some(val_r) = last_reward {
-                add_measurement!(*vis_monitor, val_r, 4);
+                add_measurement!(*vis_monitor, val_r, layout.reward);
             }
         }
     });
@@ -1,7 +1,5 @@
 use std::collections::VecDeque;
 use tracing::warn;
-use std::sync::*;
-use crate::structs::RasaVariables;
 
 pub type Measurement = egui::plot::PlotPoint;
 
@@ -9,28 +7,18 @@ pub type Measurement = egui::plot::PlotPoint;
 pub struct MeasurementWindow {
     // Values is a vector of vecdeques. The first dimension (non-deque) corresponds to each DataInputStream class
     // The second data from the deque corresponds to the plottable data
+    // One deque per channel of the session's ChannelLayout
     pub values: Vec<VecDeque<Measurement>>,
-    vars: Arc<RwLock<RasaVariables>>,
+    // Seconds kept behind the newest value, follows the X-Range slider
+    pub look_behind: usize,
     pub  rectpoints: Vec<[f64; 2]>,
 }
 
 impl MeasurementWindow {
-    /*
     pub fn new_with_look_behind(look_behind: usize, channels: usize) -> Self {
         Self {
             values: vec![VecDeque::new(); channels],
             look_behind,
-            channels,
-            rectpoints: vec![[0.0; 2]; 4],
-        }
-    }
-     */
-
-    pub fn new(program_vars: Arc<RwLock<RasaVariables>>) -> Self {
-        let var_l = program_vars.read().unwrap();
-        Self {
-            values: vec![VecDeque::new(); var_l.channels],
-            vars: Arc::clone(&program_vars),
             rectpoints: vec![[0.0; 2]; 4],
         }
     }
@@ -50,7 +38,7 @@ impl MeasurementWindow {
                 }
                 ch.push_back(measurement);
 
-                let limit = measurement.x - (self.vars.read().unwrap().look_behind as f64);
+                let limit = measurement.x - (self.look_behind as f64);
                 while let Some(front) = ch.front() {
                     if front.x >= limit {
                         break;
@@ -75,45 +63,45 @@ mod test {
 
     #[test]
     fn empty_measurements() {
-        let w = MeasurementWindow::new_with_look_behind(123);
+        let w = MeasurementWindow::new_with_look_behind(123, 0);
         assert_eq!(w.values.len(), 0);
         assert_eq!(w.look_behind, 123);
     }
 
     #[test]
     fn appends_one_value() {
-        let mut w = MeasurementWindow::new_with_look_behind(100);
+        let mut w = MeasurementWindow::new_with_look_behind(100, 1);
 
         w.add(0, Measurement::new(10.0, 20.0));
         assert_eq!(
-            w.values.into_iter().eq(vec![Measurement::new(10.0, 20.0)]),
+            w.values.remove(0).into_iter().eq(vec![Measurement::new(10.0, 20.0)]),
             true
         );
     }
 
     #[test]
     fn clears_on_out_of_order() {
-        let mut w = MeasurementWindow::new_with_look_behind(100);
+        let mut w = MeasurementWindow::new_with_look_behind(100, 1);
 
         w.add(0, Measurement::new(10.0, 20.0));
         w.add(0, Measurement::new(20.0, 30.0));
         w.add(0, Measurement::new(19.0, 100.0));
         assert_eq!(
-            w.values.into_iter().eq(vec![Measurement::new(19.0, 100.0)]),
+            w.values.remove(0).into_iter().eq(vec![Measurement::new(19.0, 100.0)]),
             true
         );
     }
 
     #[test]
     fn appends_several_values() {
-        let mut w = MeasurementWindow::new_with_look_behind(100);
+        let mut w = MeasurementWindow::new_with_look_behind(100, 1);
 
         for x in 1..=20 {
             w.add(0, Measurement::new((x as f64) * 10.0, x as f64));
         }
 
         assert_eq!(
-            w.values.into_iter().eq(vec![
+            w.values.remove(0).into_iter().eq(vec![
                 Measurement::new(100.0, 10.0),
                 Measurement::new(110.0, 11.0),
                 Measurement::new(120.0, 12.0),
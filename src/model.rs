@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::config::ModelBackend;
+use crate::templates::Template;
+use crate::util::normalize_array;
+
+#[derive(Debug)]
+pub enum ModelError {
+    Load(PathBuf, String),
+    Inference(String),
+    Unsupported(PathBuf),
+    Metadata(PathBuf, String),
+    // The model, its metadata and the session disagree about the shape of the data
+    Contract(PathBuf, Vec<String>),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Load(path, e) => write!(f, "unable to load model {:?}: {}", path, e),
+            ModelError::Inference(e) => write!(f, "model inference failed: {}", e),
+            ModelError::Unsupported(path) => write!(f, "no backend compiled in for model {:?}", path),
+            ModelError::Metadata(path, e) => write!(f, "could not read model metadata {:?}: {}", path, e),
+            ModelError::Contract(path, problems) => {
+                write!(f, "model {:?} does not fit this session:", path)?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+// A network that turns one preprocessed window into an embedding. Backends only deal with running the
+// network, preprocessing lives in Model::embed so every backend sees identical inputs
+pub trait Embedder: Send + Sync {
+    // `input` is laid out the way the networks were traced: shape (1, input.len(), 1)
+    fn forward(&self, input: &[f32]) -> Result<Vec<f64>, ModelError>;
+}
+
+#[cfg(feature = "torch")]
+pub struct TorchModel {
+    module: tch::CModule,
+}
+
+#[cfg(feature = "torch")]
+impl TorchModel {
+    pub fn load(path: &Path) -> Result<Self, ModelError> {
+        tch::CModule::load(path)
+            .map(|module| Self { module })
+            .map_err(|e| ModelError::Load(path.to_path_buf(), e.to_string()))
+    }
+}
+
+#[cfg(feature = "torch")]
+impl Embedder for TorchModel {
+    fn forward(&self, input: &[f32]) -> Result<Vec<f64>, ModelError> {
+        use tch::{Kind, Tensor};
+
+        let input_data = Tensor::of_slice(input).unsqueeze(0).unsqueeze(2);
+        let output_data = self.module.forward_ts(&[input_data])
+            .map_err(|e| ModelError::Inference(e.to_string()))?;
+        Vec::<f64>::try_from(output_data.flatten(0, -1).to_kind(Kind::Double))
+            .map_err(|e| ModelError::Inference(e.to_string()))
+    }
+}
+
+// ONNX exports of the same networks, run with tract so acquisition PCs don't need libtorch
+#[cfg(feature = "onnx")]
+pub struct OnnxModel {
+    plan: tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>,
+}
+
+#[cfg(feature = "onnx")]
+impl OnnxModel {
+    // tract optimizes for a fixed input shape, so the window length has to be known up front
+    pub fn load(path: &Path, input_len: usize) -> Result<Self, ModelError> {
+        use tract_onnx::prelude::*;
+
+        tract_onnx::onnx()
+            .model_for_path(path)
+            .and_then(|m| m.with_input_fact(0, f32::fact([1, input_len, 1]).into()))
+            .and_then(|m| m.into_optimized())
+            .and_then(|m| m.into_runnable())
+            .map(|plan| Self { plan })
+            .map_err(|e| ModelError::Load(path.to_path_buf(), e.to_string()))
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl Embedder for OnnxModel {
+    fn forward(&self, input: &[f32]) -> Result<Vec<f64>, ModelError> {
+        use tract_onnx::prelude::*;
+
+        let input_data = Tensor::from_shape(&[1, input.len(), 1], input)
+            .map_err(|e| ModelError::Inference(e.to_string()))?;
+        let outputs = self.plan.run(tvec!(input_data.into()))
+            .map_err(|e| ModelError::Inference(e.to_string()))?;
+        let output_data = outputs[0].to_array_view::<f32>()
+            .map_err(|e| ModelError::Inference(e.to_string()))?;
+        Ok(output_data.iter().map(|&x| x as f64).collect())
+    }
+}
+
+// `auto` picks the backend from the file extension: .pt is TorchScript, .onnx is ONNX.
+// Each backend needs its cargo feature (`torch`, `onnx`) compiled in
+pub fn load_model(path: &Path, backend: ModelBackend, input_len: usize) -> Result<Box<dyn Embedder>, ModelError> {
+    let backend = match (backend, path.extension().and_then(|e| e.to_str())) {
+        (ModelBackend::Auto, Some("pt")) => ModelBackend::Torch,
+        (ModelBackend::Auto, Some("onnx")) => ModelBackend::Onnx,
+        (ModelBackend::Auto, _) => return Err(ModelError::Unsupported(path.to_path_buf())),
+        (backend, _) => backend,
+    };
+
+    match backend {
+        #[cfg(feature = "torch")]
+        ModelBackend::Torch => Ok(Box::new(TorchModel::load(path)?)),
+        #[cfg(feature = "onnx")]
+        ModelBackend::Onnx => Ok(Box::new(OnnxModel::load(path, input_len)?)),
+        _ => {
+            let _ = input_len;
+            Err(ModelError::Unsupported(path.to_path_buf()))
+        }
+    }
+}
+
+// What a model expects and produces, read from the JSON file next to it (models/nested_model4.pt and
+// models/nested_model4.onnx both use models/nested_model4.json). None of this can be recovered from the
+// network itself, and getting any of it wrong gives plausible looking but meaningless distances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelMetadata {
+    #[serde(default)]
+    pub description: String,
+    // Samples per channel in one window, i.e. acquisition.window_len
+    pub input_len: usize,
+    // Names of the channels, in the order they are concatenated along the sequence axis
+    pub channels: Vec<String>,
+    pub normalization: Normalization,
+    // Number of values the network returns for one window
+    pub output_dim: usize,
+    // Template used when the config doesn't list any
+    #[serde(default)]
+    pub default_template: Option<PathBuf>,
+}
+
+// The preprocessing the model was trained with, see util::normalize_array
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Normalization {
+    // Scale the inputs were divided by after subtracting the window minimum
+    pub stddev: f64,
+    // Subtracted from each channel after centering it, so the RNN can tell the channels apart
+    pub channel_offsets: Vec<f64>,
+}
+
+impl ModelMetadata {
+    pub fn path_for(model: &Path) -> PathBuf {
+        model.with_extension("json")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ModelError> {
+        let text = fs::read_to_string(path).map_err(|e| ModelError::Metadata(path.to_path_buf(), e.to_string()))?;
+        serde_json::from_str(&text).map_err(|e| ModelError::Metadata(path.to_path_buf(), e.to_string()))
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        // The acquisition pipeline only ever produces the two photometry channels
+        if self.channels.len() != 2 {
+            problems.push(format!("metadata lists {} channels {:?} but Rasa supplies 2", self.channels.len(), self.channels));
+        }
+        if self.normalization.channel_offsets.len() != self.channels.len() {
+            problems.push(format!("metadata has {} channel offsets for {} channels",
+                self.normalization.channel_offsets.len(), self.channels.len()));
+        }
+        if !(self.normalization.stddev > 0.0) {
+            problems.push(format!("normalization stddev must be positive, got {}", self.normalization.stddev));
+        }
+        if self.input_len < 2 {
+            problems.push(format!("input_len must be at least 2, got {}", self.input_len));
+        }
+    }
+}
+
+// The model in use, shared by the detector, template capture and the model selector. A swap replaces the
+// inner Arc, so a window that is already being embedded finishes on the model it started with.
+// Lock order is templates first, then the model, so a swap can replace both without a window seeing a mix
+pub type SharedModel = Arc<RwLock<Option<Arc<Model>>>>;
+
+// A loaded network together with the metadata it was checked against
+pub struct Model {
+    pub path: PathBuf,
+    pub meta: ModelMetadata,
+    embedder: Box<dyn Embedder>,
+}
+
+impl Model {
+    // Loads the metadata sidecar and the network, then runs one blank window through it to make sure the
+    // output really has `output_dim` values
+    pub fn load(path: &Path, backend: ModelBackend) -> Result<Self, ModelError> {
+        let meta = ModelMetadata::load(&ModelMetadata::path_for(path))?;
+        let mut problems = Vec::new();
+        meta.validate(&mut problems);
+        if !problems.is_empty() {
+            return Err(ModelError::Contract(path.to_path_buf(), problems));
+        }
+
+        let input_len = meta.input_len * meta.channels.len();
+        let embedder = load_model(path, backend, input_len)?;
+        let probe = embedder.forward(&vec![0.0; input_len])?;
+        if probe.len() != meta.output_dim {
+            return Err(ModelError::Contract(path.to_path_buf(), vec![
+                format!("metadata says output_dim is {} but the network returned {} values", meta.output_dim, probe.len()),
+            ]));
+        }
+
+        Ok(Self { path: path.to_path_buf(), meta, embedder })
+    }
+
+    // Refuse sessions whose windows or templates don't have the shapes this model was built for
+    pub fn check_session(&self, window_len: usize, templates: &[Template]) -> Result<(), ModelError> {
+        let mut problems = Vec::new();
+        if window_len != self.meta.input_len {
+            problems.push(format!("acquisition.window_len is {} but the model takes {} samples per channel",
+                window_len, self.meta.input_len));
+        }
+        for t in templates.iter().filter(|t| t.vector.len() != self.meta.output_dim) {
+            problems.push(format!("template '{}' has {} values but the model outputs {}", t.name, t.vector.len(), self.meta.output_dim));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ModelError::Contract(self.path.clone(), problems))
+        }
+    }
+
+    // Normalize one window of both channels the way the model was trained and return its embedding
+    pub fn embed(&self, v0: &[f64], v1: &[f64]) -> Result<Vec<f64>, ModelError> {
+        if v0.len() != self.meta.input_len || v1.len() != self.meta.input_len {
+            return Err(ModelError::Contract(self.path.clone(), vec![
+                format!("got windows of {} and {} samples, expected {}", v0.len(), v1.len(), self.meta.input_len),
+            ]));
+        }
+        let norm = &self.meta.normalization;
+        let offsets = (norm.channel_offsets[0], norm.channel_offsets[1]);
+        let (mut input_vec, nv1) = normalize_array(v0, v1, norm.stddev, offsets);
+        input_vec.extend(nv1);
+        let input_data: Vec<f32> = input_vec.iter().map(|&x| x as f32).collect();
+
+        self.embedder.forward(&input_data)
+    }
+
+    // A model around any embedder, for tests that don't need a real network
+    #[cfg(test)]
+    pub fn with_embedder(meta: ModelMetadata, embedder: Box<dyn Embedder>) -> Self {
+        Self { path: PathBuf::from("fixture"), meta, embedder }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shipped_metadata_is_valid() {
+        let mut checked = 0;
+        for entry in fs::read_dir("models").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) != Some("pt") {
+                continue;
+            }
+            let meta = ModelMetadata::load(&ModelMetadata::path_for(&path)).unwrap();
+            let mut problems = Vec::new();
+            meta.validate(&mut problems);
+            assert!(problems.is_empty(), "{:?}: {:?}", path, problems);
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+}
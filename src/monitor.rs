@@ -8,10 +8,17 @@ use std::thread;
 use tracing::{error, info, warn};
 use std::f64;
 use std::any::type_name;
-use clap::{arg, Parser};
 use egui::plot::*;
 use egui::{Label, Button, Vec2};
 
+use crate::calibration::CalibrationPanel;
+use crate::channels::ChannelLayout;
+use crate::config::MAX_WINDOW_SECS;
+use crate::capture::TemplateCapture;
+use crate::events::{EventKind, EventLog};
+use crate::inputstream::RATE_TOLERANCE;
+use crate::selector::ModelSelector;
+use crate::safety::SafetyPanel;
 use crate::structs::RasaVariables;
 
 macro_rules! add_plot_line {
@@ -22,18 +29,60 @@ macro_rules! add_plot_line {
             $plot_ui.line(line.stroke(stroke));
         }
     };
+    ($plot_ui:expr, $color:expr, $data:expr, $channel:expr, $name:expr) => {
+        {
+            let line = egui::plot::Line::new($data.lock().unwrap().plot_values($channel)).name($name);
+            let stroke = egui::Stroke::new(2.0, $color);
+            $plot_ui.line(line.stroke(stroke));
+        }
+    };
 }
 
 
 pub struct RightSidebar {
     vars: Arc<RwLock<RasaVariables>>,
+    events: EventLog,
+    annotation: String,
+    // Values as last written to the event log
+    logged: RasaVariables,
 }
 
 impl RightSidebar {
-    pub fn new(program_vars: Arc<RwLock<RasaVariables>>) -> Self {
+    pub fn new(program_vars: Arc<RwLock<RasaVariables>>, events: EventLog) -> Self {
+        let logged = *program_vars.read().unwrap();
         Self {
             vars: program_vars,
+            events,
+            annotation: String::new(),
+            logged,
+        }
+    }
+
+    // Log changes to the parameters that affect detection and stimulation. Waits until the pointer is released
+    // so dragging a slider gives one entry rather than one per frame
+    fn log_changes(&mut self, ui: &egui::Ui) {
+        if ui.ctx().input().pointer.any_down() {
+            return;
+        }
+        let now = *self.vars.read().unwrap();
+        if now.window_secs != self.logged.window_secs {
+            self.events.log(EventKind::ParameterChange, format!("window_secs: {} -> {}", self.logged.window_secs, now.window_secs));
+            info!("Model window changed from {} s to {} s", self.logged.window_secs, now.window_secs);
         }
+        if now.threshold != self.logged.threshold {
+            self.events.log(EventKind::ParameterChange, format!("threshold: {} -> {}", self.logged.threshold, now.threshold));
+            info!("Threshold changed from {} to {}", self.logged.threshold, now.threshold);
+        }
+        if now.cooldown_secs != self.logged.cooldown_secs {
+            self.events.log(EventKind::ParameterChange, format!("cooldown_secs: {} -> {}", self.logged.cooldown_secs, now.cooldown_secs));
+            info!("Cooldown changed from {} s to {} s", self.logged.cooldown_secs, now.cooldown_secs);
+        }
+        self.logged = now;
+    }
+
+    // A threshold another panel has already logged
+    pub fn threshold_logged(&mut self, threshold: f64) {
+        self.logged.threshold = threshold;
     }
 
     pub fn show(&mut self, ui: &mut egui::Ui) {
@@ -44,48 +93,199 @@ impl RightSidebar {
 
             ui.checkbox(&mut self.vars.write().unwrap().show_box, "Show Box");
 
-            ui.add(egui::Slider::new(&mut self.vars.write().unwrap().look_behind, 0..=25).text("X-Range").integer());
-            ui.add(egui::Slider::new(&mut self.vars.write().unwrap().skip, 1..=60).text("Skip").integer());
+            ui.add(egui::Slider::new(&mut self.vars.write().unwrap().look_behind, 0..=MAX_LOOK_BEHIND).text("X-Range").integer());
+            // Drag values rather than sliders so nothing the config validation allows gets clamped to a slider's range
+            ui.horizontal(|ui| {
+                let mut vars = self.vars.write().unwrap();
+                ui.add(egui::DragValue::new(&mut vars.window_secs).speed(0.05).clamp_range(f64::MIN_POSITIVE..=MAX_WINDOW_SECS));
+                ui.label("Window (s)");
+            });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                let mut vars = self.vars.write().unwrap();
+                let speed = vars.threshold.abs().max(0.01) * 0.005;
+                ui.add(egui::DragValue::new(&mut vars.threshold).speed(speed));
+                ui.label("Threshold");
+            });
+            ui.horizontal(|ui| {
+                let mut vars = self.vars.write().unwrap();
+                let min = vars.min_cooldown_secs;
+                ui.add(egui::DragValue::new(&mut vars.cooldown_secs).speed(0.2).clamp_range(min..=u64::MAX));
+                ui.label("Cooldown (s)");
+            });
+            // Only in adaptive mode, where the z-score decides instead of the threshold above
+            if let Some(sigma) = self.vars.read().unwrap().sigma {
+                ui.label(format!("Required z-score: {:.2}", sigma));
+            }
+            if self.vars.read().unwrap().processed {
+                ui.separator();
+                ui.checkbox(&mut self.vars.write().unwrap().show_raw, "Show raw traces");
+                let vars = *self.vars.read().unwrap();
+                if let Some((delay0, delay1)) = vars.filter_delay_ms {
+                    ui.label(format!("Filter delay: {:.1} / {:.1} ms", delay0, delay1));
+                }
+                if let Some(rate) = vars.input_rate_hz {
+                    if (rate - vars.sample_rate_hz).abs() > RATE_TOLERANCE * vars.sample_rate_hz {
+                        ui.colored_label(egui::Color32::YELLOW, format!("Input at {:.1} Hz, filters designed for {} Hz", rate, vars.sample_rate_hz));
+                    } else {
+                        ui.label(format!("Input rate: {:.1} Hz", rate));
+                    }
+                }
+            }
+            self.log_changes(ui);
+
+            ui.separator();
+            ui.label("Annotation");
+            ui.text_edit_singleline(&mut self.annotation);
+            if ui.add_enabled(!self.annotation.trim().is_empty(), egui::Button::new("Add note")).clicked() {
+                self.events.log(EventKind::Annotation, self.annotation.trim());
+                self.annotation.clear();
+            }
         });
     }
 }
 
 
+// Marker color for each kind of event drawn on the plots
+fn event_color(kind: EventKind) -> egui::Color32 {
+    match kind {
+        EventKind::StimDelivered => egui::Color32::RED,
+        EventKind::StimBlocked => egui::Color32::YELLOW,
+        EventKind::TtlRise => egui::Color32::GRAY,
+        EventKind::TtlFall => egui::Color32::DARK_GRAY,
+        EventKind::ModelSwap | EventKind::ParameterChange => egui::Color32::from_rgb(100, 150, 255),
+        EventKind::EStop => egui::Color32::DARK_RED,
+        EventKind::Rearm => egui::Color32::DARK_GREEN,
+        EventKind::Annotation => egui::Color32::WHITE,
+    }
+}
+
+// Largest X-Range, which the analysis thread's sample buffer keeps enough history for template capture to cover
+pub const MAX_LOOK_BEHIND: usize = 25;
+
+// Colors for the traces after the primary signal and isosbestic, cycled through for further fibers
+const TRACE_COLORS: [egui::Color32; 4] = [
+    egui::Color32::from_rgb(120, 200, 255),
+    egui::Color32::from_rgb(255, 200, 90),
+    egui::Color32::from_rgb(200, 140, 255),
+    egui::Color32::from_rgb(255, 140, 200),
+];
+
 pub struct Plots {
     vars: Arc<RwLock<RasaVariables>>,
+    layout: Arc<ChannelLayout>,
+    events: EventLog,
 }
 
 impl Plots {
-    pub fn new(program_vars: Arc<RwLock<RasaVariables>>) -> Self {
+    pub fn new(program_vars: Arc<RwLock<RasaVariables>>, layout: Arc<ChannelLayout>, events: EventLog) -> Self {
         Self {
             vars: program_vars,
+            layout,
+            events,
+        }
+    }
+
+    // The model's pair keeps the colors it always had
+    fn trace_color(&self, channel: usize, nth_other: usize) -> egui::Color32 {
+        if channel == self.layout.signal {
+            egui::Color32::LIGHT_GREEN
+        } else if channel == self.layout.isosbestic {
+            egui::Color32::LIGHT_RED
+        } else {
+            TRACE_COLORS[nth_other % TRACE_COLORS.len()]
+        }
+    }
+
+    // A vertical line for every event still within the plots' look-behind
+    fn add_event_markers(&self, plot_ui: &mut PlotUi) {
+        let since = self.events.time() - self.vars.read().unwrap().look_behind as f64;
+        for (time, kind) in self.events.recent(since) {
+            plot_ui.vline(VLine::new(time).color(event_color(kind)).name(kind.to_string()));
         }
     }
 
-    pub fn show_measurements(&self, ui: &mut egui::Ui, measurements: &Arc<Mutex<MeasurementWindow>>) {
-        let measurement_plot = Plot::new("measurements").allow_drag(false);
+    pub fn show_measurements(&self, ui: &mut egui::Ui, measurements: &Arc<Mutex<MeasurementWindow>>, capture: Option<&mut TemplateCapture>) {
+        let measurement_plot = Plot::new("measurements").allow_drag(false).legend(Legend::default());
         measurement_plot.show(ui, |plot_ui| {
-            add_plot_line!(plot_ui, egui::Color32::LIGHT_GREEN, measurements, 0);
-            add_plot_line!(plot_ui, egui::Color32::LIGHT_RED, measurements, 1);
+            let mut others = 0;
+            for (ix, channel) in self.layout.traces() {
+                let color = self.trace_color(ix, others);
+                if ix != self.layout.signal && ix != self.layout.isosbestic {
+                    others += 1;
+                }
+                add_plot_line!(plot_ui, color, measurements, ix, channel.header());
+            }
+            self.add_event_markers(plot_ui);
 
             let series: PlotPoints = PlotPoints::new(measurements.lock().unwrap().rectpoints.clone());
             if self.vars.read().unwrap().show_box {
                 let poly = Polygon::new(series);
                 plot_ui.polygon(poly);
             }
+
+            // Clicking or dragging on the plot picks the end of the window template capture takes
+            if let Some(capture) = capture {
+                let (pressed, down) = {
+                    let pointer = &plot_ui.ctx().input().pointer;
+                    (pointer.any_pressed() && pointer.primary_down(), pointer.primary_down())
+                };
+                if let Some(pointer) = plot_ui.pointer_coordinate() {
+                    if pressed && plot_ui.plot_hovered() {
+                        capture.dragging = true;
+                        capture.selection = Some((pointer.x, pointer.x));
+                    } else if capture.dragging && down {
+                        if let Some((start, _)) = capture.selection {
+                            capture.selection = Some((start, pointer.x));
+                        }
+                    }
+                }
+                if !down {
+                    capture.dragging = false;
+                }
+
+                if let Some((start, end)) = capture.span() {
+                    let bounds = plot_ui.plot_bounds();
+                    let (bottom, top) = (bounds.min()[1], bounds.max()[1]);
+                    let span = Polygon::new(PlotPoints::new(vec![
+                        [start, bottom], [end, bottom], [end, top], [start, top],
+                    ])).color(egui::Color32::LIGHT_BLUE);
+                    plot_ui.polygon(span);
+                }
+            }
+        });
+    }
+
+    pub fn show_dff(&self, ui: &mut egui::Ui, measurements: &Arc<Mutex<MeasurementWindow>>) {
+        let dff = match self.layout.dff {
+            Some(dff) => dff,
+            None => return,
+        };
+        Plot::new("dff").allow_drag(false).show(ui, |plot_ui| {
+            add_plot_line!(plot_ui, egui::Color32::LIGHT_BLUE, measurements, dff);
+            self.add_event_markers(plot_ui);
         });
     }
 
     pub fn show_rewards(&self, ui: &mut egui::Ui, measurements: &Arc<Mutex<MeasurementWindow>>) {
+        let (threshold, adaptive_threshold) = {
+            let vars = self.vars.read().unwrap();
+            (vars.threshold, vars.adaptive_threshold)
+        };
         let mut reward_plot = egui::plot::Plot::new("rewards").allow_drag(false);
-        reward_plot = reward_plot.include_y(0.0050);
+        reward_plot = reward_plot.include_y(0.0050).include_y(threshold);
+        if let Some(adaptive) = adaptive_threshold {
+            reward_plot = reward_plot.include_y(adaptive);
+        }
         //reward_plot = reward_plot.include_y(200.0);
-        //let series: PlotPoints = PlotPoints::new(measurements.lock().unwrap().rectpoints.clone());
-        let series: Vec<[f64; 2]> = vec![[0.0, 0.0], [100.0,0.1]];
         reward_plot.show(ui, |plot_ui| {
-            add_plot_line!(plot_ui, egui::Color32::GOLD, measurements, 4);
-            let poly = Polygon::new(series);
-            plot_ui.polygon(poly);
+            add_plot_line!(plot_ui, egui::Color32::GOLD, measurements, self.layout.reward);
+            plot_ui.hline(HLine::new(threshold).color(egui::Color32::LIGHT_RED).name("threshold"));
+            if let Some(adaptive) = adaptive_threshold {
+                plot_ui.hline(HLine::new(adaptive).color(egui::Color32::from_rgb(255, 165, 0)).name("adaptive threshold"));
+            }
+            self.add_event_markers(plot_ui);
         });
 
 
@@ -101,24 +301,34 @@ pub struct MonitorApp {
 
     sidebar: RightSidebar,
     plots: Plots,
+    capture: Option<TemplateCapture>,
+    selector: Option<ModelSelector>,
+    safety: SafetyPanel,
+    calibration: CalibrationPanel,
     show_box: bool,
 }
 
 impl MonitorApp {
-    pub fn new(vars: &Arc<RwLock<RasaVariables>>) -> Self {
+    pub fn new(vars: &Arc<RwLock<RasaVariables>>, layout: Arc<ChannelLayout>, capture: Option<TemplateCapture>, selector: Option<ModelSelector>, safety: SafetyPanel, calibration: CalibrationPanel, events: EventLog) -> Self {
         let var_l = vars.read().unwrap();
         Self {
             rasa: Arc::clone(&vars),
-            measurements: Arc::new(Mutex::new(MeasurementWindow::new(
-                Arc::clone(&vars)
+            measurements: Arc::new(Mutex::new(MeasurementWindow::new_with_look_behind(
+                var_l.look_behind,
+                layout.channels.len()
             ))),
-            reward: Arc::new(Mutex::new(MeasurementWindow::new(
-                Arc::clone(&vars)
+            reward: Arc::new(Mutex::new(MeasurementWindow::new_with_look_behind(
+                var_l.look_behind,
+                1
             ))),
             feedback: Vec::new(),
 
-            sidebar: RightSidebar::new(Arc::clone(&vars)),
-            plots: Plots::new(Arc::clone(&vars)),
+            sidebar: RightSidebar::new(Arc::clone(&vars), events.clone()),
+            plots: Plots::new(Arc::clone(&vars), layout, events),
+            capture,
+            selector,
+            safety,
+            calibration,
 
             show_box: var_l.show_box
         }
@@ -143,28 +353,55 @@ impl eframe::App for MonitorApp {
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         let side_panel_width = 200.0;
+        // The X-Range slider also decides how much of each channel is kept
+        self.measurements.lock().unwrap().look_behind = self.rasa.read().unwrap().look_behind;
         egui::CentralPanel::default().show(ctx, |ui| {
             let total_height = ui.available_size().y;
-            let button_ratio = 0.7;
-            let label_ratio = 0.3;
+            let dff = self.rasa.read().unwrap().dff;
+            // ΔF/F gets a plot of its own between the traces and the reward when it is derived
+            let (button_ratio, dff_ratio, label_ratio) = if dff { (0.5, 0.25, 0.25) } else { (0.7, 0.0, 0.3) };
 
             let button_height = total_height * button_ratio;
             let label_height = total_height * label_ratio;
 
             ui.allocate_ui(Vec2::new(ui.available_size().x - side_panel_width, button_height), |ui| {
-                self.plots.show_measurements(ui, &self.measurements);
+                self.plots.show_measurements(ui, &self.measurements, self.capture.as_mut());
             });
 
+            if dff {
+                ui.allocate_ui(Vec2::new(ui.available_size().x - side_panel_width, total_height * dff_ratio), |ui| {
+                    self.plots.show_dff(ui, &self.measurements);
+                });
+            }
+
             ui.allocate_ui(Vec2::new(ui.available_size().x - side_panel_width, label_height), |ui| {
                 self.plots.show_rewards(ui, &self.measurements);
             });
         });
 
-        egui::SidePanel::right("Sidebar").show(ctx, |mut ui| {
-            self.sidebar.show(&mut ui)
+        // Scrolls once the panels outgrow the window
+        egui::SidePanel::right("Sidebar").show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |mut ui| {
+                self.sidebar.show(&mut ui);
+                ui.separator();
+                self.safety.show(&mut ui);
+                ui.separator();
+                self.calibration.show(&mut ui);
+                if let Some(threshold) = self.calibration.take_accepted() {
+                    self.sidebar.threshold_logged(threshold);
+                }
+                if let Some(selector) = self.selector.as_mut() {
+                    ui.separator();
+                    selector.show(&mut ui);
+                }
+                if let Some(capture) = self.capture.as_mut() {
+                    ui.separator();
+                    capture.show(&mut ui);
+                }
+            });
         });
 
         // make it always repaint
         ctx.request_repaint();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,360 @@
+use std::collections::VecDeque;
+
+use crate::config::{DetrendConfig, ProcessingConfig};
+use crate::filters::FilterChain;
+use crate::util::solve_linear;
+
+// A fit needs this much data, and the exponentials' time constants are searched over this range (s)
+const MIN_FIT_SECS: f64 = 30.0;
+const MIN_TAU_SECS: f64 = 10.0;
+const MAX_TAU_SECS: f64 = 20000.0;
+const TAU_STEPS: usize = 24;
+
+// One processed sample. `y0` and `y1` are the channels as the plot and the model see them
+#[derive(Debug, Clone, Copy)]
+pub struct Processed {
+    pub y0: f64,
+    pub y1: f64,
+    pub dff: Option<f64>,
+    // Trend removed from each channel, when detrending
+    pub trend: Option<(f64, f64)>,
+}
+
+// Everything derived from the raw channels as samples arrive: filtering, detrending, then ΔF/F. Shared by the
+// input stream and the offline analysis so both see the same data
+pub struct Processor {
+    filters: [Option<FilterChain>; 2],
+    detrend: Option<(Detrender, Detrender)>,
+    dff: Option<IsosbesticFit>,
+}
+
+impl Processor {
+    pub fn new(config: &ProcessingConfig) -> Self {
+        let chain = |channel: usize| config.filters.iter()
+            .find(|chain| chain.channel == channel)
+            .map(|chain| FilterChain::new(chain, config.sample_rate_hz));
+        let detrend = match config.detrend {
+            DetrendConfig::None => None,
+            method => Some((Detrender::new(method), Detrender::new(method))),
+        };
+        Self { filters: [chain(0), chain(1)], detrend, dff: config.dff.then(|| IsosbesticFit::new(config.fit_window_secs)) }
+    }
+
+    // Delay the filters add to each channel at `freq_hz`, in seconds. None without filters
+    pub fn filter_delays(&self, freq_hz: f64) -> Option<(f64, f64)> {
+        if self.filters.iter().all(Option::is_none) {
+            return None;
+        }
+        let delay = |chain: &Option<FilterChain>| chain.as_ref().map_or(0.0, |c| c.group_delay_secs(freq_hz));
+        Some((delay(&self.filters[0]), delay(&self.filters[1])))
+    }
+
+    pub fn push(&mut self, time: f64, raw0: f64, raw1: f64) -> Processed {
+        let [f0, f1] = &mut self.filters;
+        let mut y0 = f0.as_mut().map_or(raw0, |f| f.push(raw0));
+        let mut y1 = f1.as_mut().map_or(raw1, |f| f.push(raw1));
+        let mut trend = None;
+        if let Some((d0, d1)) = self.detrend.as_mut() {
+            let (t0, t1) = (d0.push(time, y0), d1.push(time, y1));
+            y0 = y0 - t0 + d0.level();
+            y1 = y1 - t1 + d1.level();
+            trend = Some((t0, t1));
+        }
+        let dff = self.dff.as_mut().map(|fit| fit.push(time, y0, y1));
+        Processed { y0, y1, dff, trend }
+    }
+}
+
+// Removes slow drift, mostly photobleaching, from one channel. The trend is subtracted and the channel's
+// starting level added back, so the detrended channel stays in its original units. The exponential methods
+// refit every `refit_secs` on one-second means of everything so far and extrapolate in between. Until the first
+// fit the trend is the starting level
+pub struct Detrender {
+    method: DetrendConfig,
+    start: Option<f64>,
+    level: f64,
+    trend: f64,
+    // High-pass: time of the previous sample
+    last_time: f64,
+    // Exponentials: one-second means (time since start, value), the bin being filled and the current fit
+    bins: Vec<(f64, f64)>,
+    bin: (f64, f64, usize),
+    fit: Option<ExpFit>,
+    last_fit: f64,
+}
+
+impl Detrender {
+    pub fn new(method: DetrendConfig) -> Self {
+        Self {
+            method,
+            start: None,
+            level: 0.0,
+            trend: 0.0,
+            last_time: 0.0,
+            bins: Vec::new(),
+            bin: (0.0, 0.0, 0),
+            fit: None,
+            last_fit: 0.0,
+        }
+    }
+
+    pub fn level(&self) -> f64 {
+        self.level
+    }
+
+    // Add a sample and return the trend at its time
+    pub fn push(&mut self, time: f64, x: f64) -> f64 {
+        if !x.is_finite() {
+            return self.trend;
+        }
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some(time);
+                self.level = x;
+                self.trend = x;
+                self.last_time = time;
+                return x;
+            }
+        };
+        let t = time - start;
+
+        match self.method {
+            DetrendConfig::None => return x,
+            DetrendConfig::HighPass { cutoff_hz } => {
+                let tau = 1.0 / (2.0 * std::f64::consts::PI * cutoff_hz);
+                let alpha = 1.0 - (-(time - self.last_time).max(0.0) / tau).exp();
+                self.trend += alpha * (x - self.trend);
+                self.last_time = time;
+            }
+            DetrendConfig::Exponential { refit_secs } | DetrendConfig::DoubleExponential { refit_secs } => {
+                if t.floor() > self.bin.0 && self.bin.2 > 0 {
+                    self.bins.push((self.bin.0 + 0.5, self.bin.1 / self.bin.2 as f64));
+                    self.bin = (t.floor(), 0.0, 0);
+                }
+                self.bin.1 += x;
+                self.bin.2 += 1;
+
+                if t >= MIN_FIT_SECS && t - self.last_fit >= refit_secs {
+                    let terms = if matches!(self.method, DetrendConfig::Exponential { .. }) { 1 } else { 2 };
+                    if let Some(fit) = fit_exponentials(&self.bins, terms, self.fit.as_ref()) {
+                        self.fit = Some(fit);
+                    }
+                    self.last_fit = t;
+                }
+                if let Some(fit) = &self.fit {
+                    self.trend = fit.at(t);
+                }
+            }
+        }
+        self.trend
+    }
+}
+
+// Sum of decaying exponentials plus an offset
+#[derive(Debug, Clone)]
+pub struct ExpFit {
+    pub taus: Vec<f64>,
+    pub amplitudes: Vec<f64>,
+    pub offset: f64,
+}
+
+impl ExpFit {
+    pub fn at(&self, t: f64) -> f64 {
+        self.offset + self.taus.iter().zip(&self.amplitudes).map(|(tau, a)| a * (-t / tau).exp()).sum::<f64>()
+    }
+}
+
+// Least squares fit of `terms` (1 or 2) exponentials plus an offset. With the time constants fixed the fit is
+// linear, so the time constants are searched on a log-spaced grid and the best one refined with shrinking steps.
+// A refit starts from the `previous` fit's time constants instead of the grid
+pub fn fit_exponentials(points: &[(f64, f64)], terms: usize, previous: Option<&ExpFit>) -> Option<ExpFit> {
+    let ratio = (MAX_TAU_SECS / MIN_TAU_SECS).powf(1.0 / (TAU_STEPS - 1) as f64);
+    let candidates: Vec<Vec<f64>> = match previous {
+        Some(previous) if previous.taus.len() == terms => vec![previous.taus.clone()],
+        _ => {
+            let taus: Vec<f64> = (0..TAU_STEPS).map(|i| MIN_TAU_SECS * ratio.powi(i as i32)).collect();
+            match terms {
+                1 => taus.iter().map(|t| vec![*t]).collect(),
+                _ => taus.iter().enumerate().flat_map(|(i, t1)| taus[i + 1..].iter().map(move |t2| vec![*t1, *t2])).collect(),
+            }
+        }
+    };
+    let (mut error, mut best) = candidates.into_iter()
+        .filter_map(|taus| fit_amplitudes(points, taus))
+        .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+    // A warm start only needs the finer steps
+    let mut step = if previous.is_some() { ratio.sqrt().sqrt() } else { ratio };
+    for _ in 0..8 {
+        step = step.sqrt();
+        let mut improved = true;
+        while improved {
+            improved = false;
+            for ix in 0..terms {
+                for factor in [step, 1.0 / step] {
+                    let mut taus = best.taus.clone();
+                    taus[ix] = (taus[ix] * factor).clamp(MIN_TAU_SECS, MAX_TAU_SECS);
+                    if let Some((e, fit)) = fit_amplitudes(points, taus) {
+                        if e < error {
+                            (error, best, improved) = (e, fit, true);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Some(best)
+}
+
+// Best amplitudes and offset for fixed time constants, with the squared error. None when the error isn't finite
+fn fit_amplitudes(points: &[(f64, f64)], taus: Vec<f64>) -> Option<(f64, ExpFit)> {
+    let rows: Vec<Vec<f64>> = points.iter()
+        .map(|(t, _)| taus.iter().map(|tau| (-t / tau).exp()).chain(std::iter::once(1.0)).collect())
+        .collect();
+    let y: Vec<f64> = points.iter().map(|(_, v)| *v).collect();
+    let coefficients = least_squares(&rows, &y)?;
+    let terms = taus.len();
+    let fit = ExpFit { taus, amplitudes: coefficients[..terms].to_vec(), offset: coefficients[terms] };
+    let error: f64 = points.iter().map(|(t, v)| (fit.at(*t) - v).powi(2)).sum();
+    if !error.is_finite() {
+        return None;
+    }
+    Some((error, fit))
+}
+
+// Least squares through the normal equations. None when they are singular
+fn least_squares(rows: &[Vec<f64>], y: &[f64]) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+    if rows.len() < n {
+        return None;
+    }
+    // Augmented [AᵀA | Aᵀy]
+    let mut m = vec![vec![0.0; n + 1]; n];
+    for (row, v) in rows.iter().zip(y) {
+        for i in 0..n {
+            for j in 0..n {
+                m[i][j] += row[i] * row[j];
+            }
+            m[i][n] += row[i] * v;
+        }
+    }
+    solve_linear(m)
+}
+
+// Motion and bleaching correction with the isosbestic channel. The isosbestic is fitted to the signal by least
+// squares over the last `window_secs` of data and ΔF/F is the signal's deviation from that fit, relative to
+// the fit. Updated with every sample from running sums, so it costs the same at any sample rate
+pub struct IsosbesticFit {
+    window_secs: f64,
+    // (time, signal, isosbestic)
+    samples: VecDeque<(f64, f64, f64)>,
+    sum_iso: f64,
+    sum_signal: f64,
+    sum_iso_sq: f64,
+    sum_iso_signal: f64,
+}
+
+impl IsosbesticFit {
+    pub fn new(window_secs: f64) -> Self {
+        Self {
+            window_secs,
+            samples: VecDeque::new(),
+            sum_iso: 0.0,
+            sum_signal: 0.0,
+            sum_iso_sq: 0.0,
+            sum_iso_signal: 0.0,
+        }
+    }
+
+    // Add a sample and return its ΔF/F. 0 until the isosbestic has varied enough to fit
+    pub fn push(&mut self, time: f64, signal: f64, iso: f64) -> f64 {
+        if !signal.is_finite() || !iso.is_finite() {
+            return 0.0;
+        }
+        self.samples.push_back((time, signal, iso));
+        self.add(signal, iso, 1.0);
+        while let Some((t, s, i)) = self.samples.front().copied() {
+            if t >= time - self.window_secs {
+                break;
+            }
+            self.samples.pop_front();
+            self.add(s, i, -1.0);
+        }
+
+        match self.coefficients() {
+            Some((slope, intercept)) => {
+                let fitted = slope * iso + intercept;
+                if fitted.abs() > f64::EPSILON { (signal - fitted) / fitted } else { 0.0 }
+            }
+            None => 0.0,
+        }
+    }
+
+    fn add(&mut self, signal: f64, iso: f64, sign: f64) {
+        self.sum_iso += sign * iso;
+        self.sum_signal += sign * signal;
+        self.sum_iso_sq += sign * iso * iso;
+        self.sum_iso_signal += sign * iso * signal;
+    }
+
+    // Slope and intercept of signal = slope * isosbestic + intercept
+    pub fn coefficients(&self) -> Option<(f64, f64)> {
+        let n = self.samples.len() as f64;
+        if n < 2.0 {
+            return None;
+        }
+        let var_iso = self.sum_iso_sq / n - (self.sum_iso / n).powi(2);
+        if !(var_iso > 1e-12) {
+            return None;
+        }
+        let cov = self.sum_iso_signal / n - (self.sum_iso / n) * (self.sum_signal / n);
+        let slope = cov / var_iso;
+        Some((slope, self.sum_signal / n - slope * self.sum_iso / n))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fits_bleaching_decay() {
+        let decay = |t: f64| 300.0 * (-t / 600.0).exp() + 100.0 * (-t / 40.0).exp() + 500.0;
+        let points: Vec<(f64, f64)> = (0..1800).map(|t| (t as f64, decay(t as f64))).collect();
+        let single = fit_exponentials(&points, 1, None).unwrap();
+        let double = fit_exponentials(&points, 2, None).unwrap();
+        let worst = |fit: &ExpFit| points.iter().map(|(t, v)| (fit.at(*t) - v).abs()).fold(0.0, f64::max);
+        assert!(worst(&double) < 5.0, "{:?}", double);
+        assert!(worst(&double) < worst(&single));
+
+        let mut detrender = Detrender::new(DetrendConfig::DoubleExponential { refit_secs: 10.0 });
+        let mut detrended = 0.0;
+        for i in 0..18000 {
+            let t = i as f64 * 0.1;
+            detrended = decay(t) - detrender.push(t, decay(t)) + detrender.level();
+        }
+        assert!((detrended - decay(0.0)).abs() < 5.0, "{}", detrended);
+    }
+
+    #[test]
+    fn removes_shared_motion() {
+        let mut fit = IsosbesticFit::new(10.0);
+        let mut dff = 0.0;
+        // Both channels share an artifact, the signal also has a 5% transient at the end
+        for i in 0..2000 {
+            let t = i as f64 * 0.01;
+            let motion = (t * 3.0).sin() * 20.0;
+            let iso = 100.0 + motion;
+            let signal = 2.0 * iso + 50.0;
+            let transient = if i >= 1990 { 1.05 } else { 1.0 };
+            dff = fit.push(t, signal * transient, iso);
+            if i == 1980 {
+                assert!(dff.abs() < 1e-6, "{}", dff);
+            }
+        }
+        let (slope, intercept) = fit.coefficients().unwrap();
+        assert!((slope - 2.0).abs() < 0.05 && (intercept - 50.0).abs() < 5.0, "{} {}", slope, intercept);
+        assert!((dff - 0.05).abs() < 0.01, "{}", dff);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::detector::Window;
+
+// The decimation low-pass: a Blackman-windowed sinc cut off at 0.4 × the output rate, reaching this many output
+// steps either side. Flat to a quarter of the output rate, 20 dB down at the new Nyquist frequency and 55 dB down
+// from 0.6 × the output rate
+const CUTOFF: f64 = 0.4;
+const KERNEL_STEPS: f64 = 6.0;
+
+// Processed samples at the input rate, kept for a little longer than the model window plus `history_secs`. The
+// stream pushes every sample, the analysis thread takes a fixed-duration window resampled to the model's length
+// whatever the rate, and template capture takes older spans from the history
+pub struct WindowBuffer {
+    window_len: usize,
+    history_secs: f64,
+    times: VecDeque<f64>,
+    v0: VecDeque<f64>,
+    v1: VecDeque<f64>,
+    dff: VecDeque<f64>,
+}
+
+// A model window resampled from the buffer, `window_len` points evenly spaced over exactly `window_secs`
+pub struct ResampledWindow {
+    pub times: Vec<f64>,
+    pub v0: Vec<f64>,
+    pub v1: Vec<f64>,
+    pub dff: Vec<f64>,
+}
+
+impl ResampledWindow {
+    pub fn as_window(&self) -> Window<'_> {
+        Window { times: &self.times, v0: &self.v0, v1: &self.v1, dff: &self.dff }
+    }
+}
+
+impl WindowBuffer {
+    pub fn new(window_len: usize, history_secs: f64) -> Self {
+        Self { window_len, history_secs, times: VecDeque::new(), v0: VecDeque::new(), v1: VecDeque::new(), dff: VecDeque::new() }
+    }
+
+    // `dff` is None unless ΔF/F is derived. Samples older than the window plus the resampling margin are dropped
+    pub fn push(&mut self, time: f64, v0: f64, v1: f64, dff: Option<f64>, window_secs: f64) {
+        // A replay starting over
+        if self.times.back().map_or(false, |last| time < *last) {
+            self.times.clear();
+            self.v0.clear();
+            self.v1.clear();
+            self.dff.clear();
+        }
+        self.times.push_back(time);
+        self.v0.push_back(v0);
+        self.v1.push_back(v1);
+        if let Some(dff) = dff {
+            self.dff.push_back(dff);
+        }
+
+        let keep = window_secs + (KERNEL_STEPS + 1.0) * window_secs / (self.window_len - 1) as f64 + 1.0 + self.history_secs;
+        while self.times.front().map_or(false, |t| *t < time - keep) {
+            self.times.pop_front();
+            self.v0.pop_front();
+            self.v1.pop_front();
+            self.dff.pop_front();
+        }
+    }
+
+    // The window ending at the newest sample. None until the buffer spans `window_secs`
+    pub fn window(&mut self, window_secs: f64) -> Option<ResampledWindow> {
+        let end = *self.times.back()?;
+        self.window_ending(end, window_secs)
+    }
+
+    // The window the analysis thread saw, or would have seen, when the sample at `end` arrived: only samples up
+    // to `end` go in. None unless the buffer covers it
+    pub fn window_ending(&mut self, end: f64, window_secs: f64) -> Option<ResampledWindow> {
+        let start = end - window_secs;
+        if *self.times.front()? > start || *self.times.back()? < end {
+            return None;
+        }
+        let times = self.times.make_contiguous();
+        let n = times.partition_point(|t| *t <= end);
+        let times = &times[..n];
+        let channel = |values: &mut VecDeque<f64>| {
+            if values.is_empty() { Vec::new() } else { resample(times, &values.make_contiguous()[..n], start, end, self.window_len) }
+        };
+        Some(ResampledWindow {
+            times: grid(start, end, self.window_len),
+            v0: channel(&mut self.v0),
+            v1: channel(&mut self.v1),
+            dff: channel(&mut self.dff),
+        })
+    }
+}
+
+fn grid(start: f64, end: f64, len: usize) -> Vec<f64> {
+    match len {
+        0 => Vec::new(),
+        1 => vec![end],
+        _ => (0..len).map(|i| start + (end - start) * i as f64 / (len - 1) as f64).collect(),
+    }
+}
+
+// Resample irregularly timed `values` onto `len` evenly spaced points from `start` to `end`. Each point is a
+// weighted average of the samples around it. Decimating, the weights are the windowed-sinc low-pass above, so
+// what the output rate can't represent is filtered out before it folds back in. Upsampling, they are a triangle
+// one input step either side, which is exactly linear interpolation. At the ends only the samples on one side
+// are available
+pub fn resample(times: &[f64], values: &[f64], start: f64, end: f64, len: usize) -> Vec<f64> {
+    let n = times.len().min(values.len());
+    if n == 0 {
+        return vec![0.0; len];
+    }
+    let (times, values) = (&times[..n], &values[..n]);
+    let output_step = if len > 1 { (end - start) / (len - 1) as f64 } else { 0.0 };
+    let input_step = if n > 1 { (times[n - 1] - times[0]) / (n - 1) as f64 } else { 0.0 };
+    let decimating = output_step > input_step;
+    let half_width = if decimating { KERNEL_STEPS * output_step } else { input_step };
+    let weight = |d: f64| {
+        if !decimating {
+            return 1.0 - d.abs() / half_width;
+        }
+        let x = 2.0 * CUTOFF * d / output_step;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+        let phase = PI * d / half_width;
+        sinc * (0.42 + 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+    };
+
+    grid(start, end, len).into_iter().map(|t| {
+        let from = times.partition_point(|x| *x < t - half_width);
+        let to = times.partition_point(|x| *x <= t + half_width);
+        let (mut sum, mut weights) = (0.0, 0.0);
+        for i in from..to {
+            let w = weight(times[i] - t);
+            sum += w * values[i];
+            weights += w;
+        }
+        if weights > 0.0 {
+            return sum / weights;
+        }
+        // A gap in the data, or a single sample: the nearest sample
+        let after = times.partition_point(|x| *x < t).min(n - 1);
+        let before = after.saturating_sub(1);
+        if (t - times[before]).abs() <= (times[after] - t).abs() { values[before] } else { values[after] }
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn windows_span_a_fixed_duration() {
+        // The same 2 s window from 50 Hz and 1 kHz data, with a 200 Hz tone on top that 10 points can't represent
+        let slow = |t: f64| (2.0 * PI * 0.25 * t).sin();
+        for rate in [50.0, 1000.0] {
+            let mut buffer = WindowBuffer::new(10, 0.0);
+            for i in 0..(rate as usize * 4) {
+                let t = i as f64 / rate;
+                let tone = if rate > 400.0 { (2.0 * PI * 200.0 * t).sin() } else { 0.0 };
+                buffer.push(t, slow(t) + tone, 1.0, None, 2.0);
+                if i == rate as usize {
+                    assert!(buffer.window(2.0).is_none());
+                }
+            }
+            let window = buffer.window(2.0).unwrap();
+            let end = (rate * 4.0 - 1.0) / rate;
+            // A past window leaves out the samples after it
+            let past = buffer.window_ending(3.0, 2.0).unwrap();
+            assert_eq!((past.times[0], past.times[9]), (1.0, 3.0));
+            assert!((past.v0[9] - slow(3.0)).abs() < 0.05, "{} Hz: {}", rate, past.v0[9]);
+            assert!((window.times[0] - (end - 2.0)).abs() < 1e-9 && (window.times[9] - end).abs() < 1e-9);
+            assert!(window.dff.is_empty());
+            for (t, v) in window.times.iter().zip(&window.v0).take(9) {
+                assert!((v - slow(*t)).abs() < 0.05, "{} Hz: {} at {}", rate, v, t);
+            }
+        }
+        // A tone between the output Nyquist frequency and the output rate would otherwise alias to a slow wave
+        let times: Vec<f64> = (0..10000).map(|i| i as f64 / 1000.0).collect();
+        for freq in [6.0, 8.0, 10.0] {
+            let tone: Vec<f64> = times.iter().map(|t| (2.0 * PI * freq * t).sin()).collect();
+            let out = resample(&times, &tone, 2.0, 8.0, 61);
+            assert!(out.iter().all(|v| v.abs() < 0.01), "{} Hz: {:?}", freq, out);
+        }
+        // Upsampling interpolates linearly
+        assert_eq!(resample(&[0.0, 1.0, 2.0], &[0.0, 10.0, 0.0], 0.0, 2.0, 5), vec![0.0, 5.0, 10.0, 5.0, 0.0]);
+    }
+}
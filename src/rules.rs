@@ -0,0 +1,179 @@
+use crate::config::{RuleCombine, RuleCondition, RuleConfig};
+use crate::detector::{Detection, Window};
+use crate::inputstream::ChannelBaseline;
+use crate::stim::qualifies_for_stimulation;
+
+// What the rules get to look at for one window
+pub struct RuleInput<'a> {
+    // Data time of the newest sample in the window
+    pub time: f64,
+    pub window: &'a Window<'a>,
+    pub detection: &'a Detection,
+    // The primary decision: the detector's threshold, or the adaptive z-score when that is enabled
+    pub fired: bool,
+    pub ttl: bool,
+    pub baseline: ChannelBaseline,
+}
+
+// Names of the rules that passed and failed on one window, nested rules included
+#[derive(Debug, Default)]
+pub struct RuleOutcome {
+    pub pass: bool,
+    pub passed: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+// The conditions from [[detection.rules]], combined with `combine_rules`. Every rule is evaluated on every
+// window, so rules with `within_secs` keep track of when they last held even while another rule fails
+pub struct RuleEngine {
+    combine: RuleCombine,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    name: String,
+    within_secs: Option<f64>,
+    condition: Condition,
+    last_pass: Option<f64>,
+}
+
+enum Condition {
+    Leaf(RuleCondition),
+    All(Vec<Rule>),
+    Any(Vec<Rule>),
+}
+
+impl RuleEngine {
+    // Without configured rules a stimulation needs the detector to fire while TTL is high, as it always has
+    pub fn new(combine: RuleCombine, rules: &[RuleConfig]) -> Self {
+        if rules.is_empty() {
+            let defaults = [
+                RuleConfig { name: String::from("detector"), within_secs: None, when: RuleCondition::Detector },
+                RuleConfig { name: String::from("ttl"), within_secs: None, when: RuleCondition::Ttl { high: true } },
+            ];
+            return Self { combine: RuleCombine::All, rules: defaults.iter().map(Rule::new).collect() };
+        }
+        Self { combine, rules: rules.iter().map(Rule::new).collect() }
+    }
+
+    pub fn evaluate(&mut self, input: &RuleInput) -> RuleOutcome {
+        let mut outcome = RuleOutcome::default();
+        outcome.pass = combine(self.combine, &mut self.rules, input, &mut outcome);
+        outcome
+    }
+}
+
+fn combine(combine: RuleCombine, rules: &mut [Rule], input: &RuleInput, outcome: &mut RuleOutcome) -> bool {
+    let results: Vec<bool> = rules.iter_mut().map(|rule| rule.evaluate(input, outcome)).collect();
+    match combine {
+        RuleCombine::All => results.iter().all(|r| *r),
+        RuleCombine::Any => results.iter().any(|r| *r),
+    }
+}
+
+impl Rule {
+    fn new(config: &RuleConfig) -> Self {
+        let condition = match &config.when {
+            RuleCondition::All { rules } => Condition::All(rules.iter().map(Rule::new).collect()),
+            RuleCondition::Any { rules } => Condition::Any(rules.iter().map(Rule::new).collect()),
+            leaf => Condition::Leaf(leaf.clone()),
+        };
+        Self { name: config.name.clone(), within_secs: config.within_secs, condition, last_pass: None }
+    }
+
+    fn evaluate(&mut self, input: &RuleInput, outcome: &mut RuleOutcome) -> bool {
+        let holds = match &mut self.condition {
+            Condition::Leaf(condition) => holds(condition, input),
+            Condition::All(rules) => combine(RuleCombine::All, rules, input, outcome),
+            Condition::Any(rules) => combine(RuleCombine::Any, rules, input, outcome),
+        };
+        if holds {
+            self.last_pass = Some(input.time);
+        }
+        let pass = match (self.within_secs, self.last_pass) {
+            (Some(within), Some(last)) => input.time - last <= within,
+            _ => holds,
+        };
+        if pass {
+            outcome.passed.push(self.name.clone());
+        } else {
+            outcome.failed.push(self.name.clone());
+        }
+        pass
+    }
+}
+
+fn holds(condition: &RuleCondition, input: &RuleInput) -> bool {
+    let baseline = input.baseline;
+    match condition {
+        RuleCondition::Detector => input.fired,
+        RuleCondition::Score { above } => input.detection.score > *above,
+        RuleCondition::ChannelZ { channel, recent, above, below } => {
+            let values = input.window.channel(*channel);
+            let (average, std) = if *channel == 0 { (baseline.average.0, baseline.std.0) } else { (baseline.average.1, baseline.std.1) };
+            if values.is_empty() || !(std > 0.0) {
+                return false;
+            }
+            let newest = &values[values.len().saturating_sub(*recent)..];
+            let z = (newest.iter().sum::<f64>() / newest.len() as f64 - average) / std;
+            above.map_or(true, |a| z > a) && below.map_or(true, |b| z < b)
+        }
+        RuleCondition::ChannelPercentile { sigma, fraction } =>
+            qualifies_for_stimulation(input.window.v0, input.window.v1, baseline.average, baseline.std, *sigma, *fraction),
+        RuleCondition::SignalQuality { channel, min_std, max_abs, max_gap_secs } => {
+            let values = input.window.channel(*channel);
+            if values.is_empty() || values.iter().any(|v| !v.is_finite()) {
+                return false;
+            }
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64).sqrt();
+            let largest = values.iter().fold(0f64, |m, v| m.max(v.abs()));
+            let gap = input.window.times.windows(2).map(|t| t[1] - t[0]).fold(0f64, f64::max);
+            min_std.map_or(true, |m| std >= m)
+                && max_abs.map_or(true, |m| largest <= m)
+                && max_gap_secs.map_or(true, |m| gap <= m)
+        }
+        RuleCondition::Ttl { high } => input.ttl == *high,
+        // Groups are built into Condition::All/Any
+        RuleCondition::All { .. } | RuleCondition::Any { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn combines_rules_with_time_windows() {
+        let rules: Vec<RuleConfig> = toml::from_str::<toml::Value>(r#"
+            rules = [
+                { name = "detector", when = { kind = "detector" } },
+                { name = "recent ttl", within_secs = 2.0, when = { kind = "ttl" } },
+                { name = "quality or score", when = { kind = "any", rules = [
+                    { name = "quality", when = { kind = "signal_quality", max_abs = 10.0 } },
+                    { name = "score", when = { kind = "score", above = 5.0 } },
+                ] } },
+            ]
+        "#).unwrap()["rules"].clone().try_into().unwrap();
+        let mut engine = RuleEngine::new(RuleCombine::All, &rules);
+
+        let times = [0.0, 0.1, 0.2];
+        let (v0, v1) = ([1.0, 2.0, 20.0], [1.0, 1.0, 1.0]);
+        let window = Window { times: &times, v0: &v0, v1: &v1, dff: &[] };
+        let detection = Detection { score: 6.0, fire: true, label: String::new() };
+        let mut input = RuleInput { time: 0.2, window: &window, detection: &detection, fired: true, ttl: true, baseline: ChannelBaseline::default() };
+
+        let outcome = engine.evaluate(&input);
+        assert!(outcome.pass);
+        assert_eq!(outcome.failed, vec!["quality"]);
+
+        // TTL dropped a second ago still counts, three seconds ago it doesn't
+        input.ttl = false;
+        input.time = 1.2;
+        assert!(engine.evaluate(&input).pass);
+        input.time = 3.2;
+        let outcome = engine.evaluate(&input);
+        assert!(!outcome.pass);
+        assert!(outcome.failed.contains(&String::from("recent ttl")));
+    }
+}
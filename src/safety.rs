@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::*;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::config::SafetyConfig;
+use crate::events::{EventKind, EventLog};
+use crate::stim::StimStop;
+
+// Why a stimulation that passed detection, cooldown and TTL was not delivered
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockReason {
+    EStop,
+    PerMinute(u32),
+    PerHour(u32),
+    PerSession(u32),
+    StimTime(f64),
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockReason::EStop => write!(f, "emergency stop is latched"),
+            BlockReason::PerMinute(max) => write!(f, "limit of {} stimulations per minute reached", max),
+            BlockReason::PerHour(max) => write!(f, "limit of {} stimulations per hour reached", max),
+            BlockReason::PerSession(max) => write!(f, "limit of {} stimulations per session reached", max),
+            BlockReason::StimTime(max) => write!(f, "limit of {} s cumulative stimulation time reached", max),
+        }
+    }
+}
+
+// Everything that gates stimulation apart from the detector and TTL: arming, the cooldown and the hard
+// limits. Shared by the analysis thread and the sidebar. Every stimulation, automatic or manual, has to be
+// out of cooldown and pass `check`, and is counted with `record` once it is sent
+pub struct Interlock {
+    limits: SafetyConfig,
+    // Closed-loop stimulation only happens while armed. Manual stimulations don't need it
+    pub armed: bool,
+    cooldown: Duration,
+    zapper_timer: Instant,
+    manual_requested: bool,
+    // Duration of one run of the stimulation protocol
+    stim_secs: f64,
+    // Delivery times within the last hour
+    recent: VecDeque<Instant>,
+    pub total: u32,
+    pub cumulative_secs: f64,
+    estop: bool,
+}
+
+impl Interlock {
+    pub fn new(limits: SafetyConfig, stim_secs: f64, cooldown: Duration) -> Self {
+        Self {
+            armed: limits.start_armed,
+            limits,
+            cooldown,
+            zapper_timer: Instant::now(),
+            manual_requested: false,
+            stim_secs,
+            recent: VecDeque::new(),
+            total: 0,
+            cumulative_secs: 0.0,
+            estop: false,
+        }
+    }
+
+    pub fn set_cooldown(&mut self, cooldown: Duration) {
+        self.cooldown = cooldown;
+    }
+
+    pub fn cooldown_remaining(&self, now: Instant) -> Duration {
+        self.cooldown.saturating_sub(now.duration_since(self.zapper_timer))
+    }
+
+    // Restart the cooldown if it has run out. Returns false while still cooling down
+    pub fn start_cooldown(&mut self, now: Instant) -> bool {
+        if self.cooldown_remaining(now) > Duration::ZERO {
+            return false;
+        }
+        self.zapper_timer = now;
+        true
+    }
+
+    pub fn request_manual(&mut self) {
+        self.manual_requested = true;
+    }
+
+    pub fn take_manual(&mut self) -> bool {
+        std::mem::take(&mut self.manual_requested)
+    }
+
+    pub fn check(&self, now: Instant) -> Result<(), BlockReason> {
+        let within = |window: Duration| self.recent.iter().filter(|t| now.duration_since(**t) < window).count() as u32;
+
+        if self.estop {
+            Err(BlockReason::EStop)
+        } else if self.total >= self.limits.max_per_session {
+            Err(BlockReason::PerSession(self.limits.max_per_session))
+        } else if self.cumulative_secs + self.stim_secs > self.limits.max_stim_secs {
+            Err(BlockReason::StimTime(self.limits.max_stim_secs))
+        } else if within(Duration::from_secs(3600)) >= self.limits.max_per_hour {
+            Err(BlockReason::PerHour(self.limits.max_per_hour))
+        } else if within(Duration::from_secs(60)) >= self.limits.max_per_minute {
+            Err(BlockReason::PerMinute(self.limits.max_per_minute))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.recent.push_back(now);
+        while self.recent.front().map_or(false, |t| now.duration_since(*t) >= Duration::from_secs(3600)) {
+            self.recent.pop_front();
+        }
+        self.total += 1;
+        self.cumulative_secs += self.stim_secs;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.estop
+    }
+}
+
+// Arm/disarm, manual stimulation, the E-stop and the live cooldown and count. The E-stop latches until
+// re-armed and also aborts a train the device is running
+pub struct SafetyPanel {
+    interlock: Arc<Mutex<Interlock>>,
+    stop: Option<StimStop>,
+    // Blinded label of the experimental condition
+    condition: String,
+    events: EventLog,
+}
+
+impl SafetyPanel {
+    pub fn new(interlock: Arc<Mutex<Interlock>>, stop: Option<StimStop>, condition: String, events: EventLog) -> Self {
+        Self { interlock, stop, condition, events }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.label("Stimulation");
+        if !self.condition.is_empty() {
+            ui.label(format!("Condition: {}", self.condition));
+        }
+        let (stopped, armed) = {
+            let interlock = self.interlock.lock().unwrap();
+            (interlock.is_stopped(), interlock.armed)
+        };
+
+        ui.horizontal(|ui| {
+            let label = if armed { "Disarm" } else { "Arm" };
+            if ui.add_enabled(!stopped, egui::Button::new(label)).clicked() {
+                self.interlock.lock().unwrap().armed = !armed;
+                info!("Closed-loop stimulation {}", if armed { "disarmed" } else { "armed" });
+                self.events.log(EventKind::ParameterChange, format!("armed: {} -> {}", armed, !armed));
+            }
+            if ui.add_enabled(!stopped, egui::Button::new("Manual stim")).clicked() {
+                self.interlock.lock().unwrap().request_manual();
+                info!("Manual stimulation requested");
+            }
+        });
+        if armed {
+            ui.colored_label(egui::Color32::LIGHT_GREEN, "Armed");
+        } else {
+            ui.label("Disarmed");
+        }
+
+        let estop = egui::Button::new(egui::RichText::new("E-STOP").strong().color(egui::Color32::WHITE))
+            .fill(egui::Color32::DARK_RED);
+        if ui.add_enabled(!stopped, estop).clicked() {
+            self.interlock.lock().unwrap().estop = true;
+            warn!("Emergency stop pressed, stimulation is latched off");
+            self.events.log(EventKind::EStop, String::new());
+            if let Some(stop) = self.stop.as_mut() {
+                if let Err(e) = stop.stop() {
+                    error!("Could not stop the stimulator: {}", e);
+                }
+            }
+        }
+
+        if stopped {
+            ui.colored_label(egui::Color32::RED, "Stimulation stopped");
+            if ui.button("Re-arm").clicked() {
+                self.interlock.lock().unwrap().estop = false;
+                info!("Stimulation re-armed after emergency stop");
+                self.events.log(EventKind::Rearm, String::new());
+            }
+        }
+
+        let interlock = self.interlock.lock().unwrap();
+        let now = Instant::now();
+        ui.label(format!("Cooldown: {:.1} s", interlock.cooldown_remaining(now).as_secs_f64()));
+        ui.label(format!("Stimulations: {} ({:.1} s)", interlock.total, interlock.cumulative_secs));
+        if let Err(reason) = interlock.check(now) {
+            if reason != BlockReason::EStop {
+                ui.colored_label(egui::Color32::YELLOW, format!("Blocked: {}", reason));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> SafetyConfig {
+        SafetyConfig { max_per_minute: 2, max_per_hour: 3, max_per_session: 4, max_stim_secs: 100.0, start_armed: true }
+    }
+
+    #[test]
+    fn enforces_rate_limits() {
+        let mut interlock = Interlock::new(limits(), 1.0, Duration::ZERO);
+        let start = Instant::now();
+        interlock.record(start);
+        interlock.record(start + Duration::from_secs(1));
+        assert_eq!(interlock.check(start + Duration::from_secs(2)), Err(BlockReason::PerMinute(2)));
+        assert_eq!(interlock.check(start + Duration::from_secs(61)), Ok(()));
+        interlock.record(start + Duration::from_secs(61));
+        assert_eq!(interlock.check(start + Duration::from_secs(200)), Err(BlockReason::PerHour(3)));
+        interlock.record(start + Duration::from_secs(3700));
+        assert_eq!(interlock.check(start + Duration::from_secs(3800)), Err(BlockReason::PerSession(4)));
+    }
+
+    #[test]
+    fn estop_latches_until_rearmed() {
+        let mut interlock = Interlock::new(limits(), 60.0, Duration::ZERO);
+        interlock.estop = true;
+        assert_eq!(interlock.check(Instant::now()), Err(BlockReason::EStop));
+        interlock.estop = false;
+        interlock.record(Instant::now());
+        assert_eq!(interlock.check(Instant::now()), Err(BlockReason::StimTime(100.0)));
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::*;
+use std::thread;
+use tracing::{error, info, warn};
+
+use crate::config::ModelBackend;
+use crate::events::{EventKind, EventLog};
+use crate::model::{Model, ModelMetadata, SharedModel};
+use crate::templates::Template;
+
+// Lists the models next to the configured one and swaps the live model without stopping acquisition.
+// Loading (and the test inference) happens on a background thread, then the model is swapped into the
+// shared slot in one step and the swap is recorded in the event log
+pub struct ModelSelector {
+    model: SharedModel,
+    templates: Arc<RwLock<Vec<Template>>>,
+    backend: ModelBackend,
+    window_len: usize,
+    model_dir: PathBuf,
+    events: EventLog,
+
+    available: Vec<(PathBuf, Result<ModelMetadata, String>)>,
+    selected: usize,
+    loading: Option<Receiver<Result<String, String>>>,
+    status: String,
+}
+
+impl ModelSelector {
+    pub fn new(model: SharedModel, templates: Arc<RwLock<Vec<Template>>>, backend: ModelBackend, window_len: usize, model_dir: PathBuf, events: EventLog) -> Self {
+        let mut selector = Self {
+            model,
+            templates,
+            backend,
+            window_len,
+            model_dir,
+            events,
+            available: Vec::new(),
+            selected: 0,
+            loading: None,
+            status: String::new(),
+        };
+        selector.rescan();
+        selector
+    }
+
+    // Every .pt and .onnx file in the model directory, with its metadata or the reason it has none
+    pub fn rescan(&mut self) {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.model_dir)
+            .map(|dir| dir.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+            .unwrap_or_default();
+        paths.retain(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("pt") | Some("onnx")));
+        paths.sort();
+
+        self.available = paths.into_iter()
+            .map(|p| {
+                let meta = ModelMetadata::load(&ModelMetadata::path_for(&p)).map_err(|e| e.to_string());
+                (p, meta)
+            })
+            .collect();
+        let current = self.current_path();
+        self.selected = self.available.iter().position(|(p, _)| Some(p) == current.as_ref()).unwrap_or(0);
+    }
+
+    fn current_path(&self) -> Option<PathBuf> {
+        self.model.read().unwrap().as_ref().map(|m| m.path.clone())
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        if let Some(rx) = &self.loading {
+            if let Ok(result) = rx.try_recv() {
+                self.status = match result {
+                    Ok(status) => status,
+                    Err(e) => e,
+                };
+                self.loading = None;
+            }
+        }
+
+        ui.label("Model");
+        match self.current_path() {
+            Some(path) => { ui.label(format!("In use: {}", file_name(&path))); },
+            None => { ui.label("In use: none"); },
+        }
+
+        let selected_text = self.available.get(self.selected).map(|(p, _)| file_name(p)).unwrap_or_default();
+        egui::ComboBox::from_id_source("model_selector")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                for (ix, (path, _)) in self.available.iter().enumerate() {
+                    ui.selectable_value(&mut self.selected, ix, file_name(path));
+                }
+            });
+
+        let mut loadable = false;
+        match self.available.get(self.selected) {
+            Some((_, Ok(meta))) => {
+                if !meta.description.is_empty() {
+                    ui.label(&meta.description);
+                }
+                ui.label(format!("Input: {} x {} ({})", meta.channels.len(), meta.input_len, meta.channels.join(", ")));
+                ui.label(format!("Output: {} values", meta.output_dim));
+                if let Some(template) = &meta.default_template {
+                    ui.label(format!("Default template: {}", template.display()));
+                }
+                loadable = true;
+            }
+            Some((_, Err(e))) => { ui.label(format!("No usable metadata: {}", e)); },
+            None => { ui.label(format!("No models in {:?}", self.model_dir)); },
+        }
+
+        ui.horizontal(|ui| {
+            let can_load = loadable && self.loading.is_none();
+            if ui.add_enabled(can_load, egui::Button::new("Load")).clicked() {
+                self.load_selected();
+            }
+            if ui.add_enabled(self.loading.is_none(), egui::Button::new("Rescan")).clicked() {
+                self.rescan();
+            }
+        });
+
+        if self.loading.is_some() {
+            ui.label("Loading...");
+        } else if !self.status.is_empty() {
+            ui.label(&self.status);
+        }
+    }
+
+    fn load_selected(&mut self) {
+        let path = match self.available.get(self.selected) {
+            Some((path, _)) => path.clone(),
+            None => return,
+        };
+        let (tx, rx) = channel();
+        let (model, templates) = (Arc::clone(&self.model), Arc::clone(&self.templates));
+        let (backend, window_len) = (self.backend, self.window_len);
+        let events = self.events.clone();
+
+        info!("Loading model {:?} in the background", path);
+        thread::spawn(move || {
+            let result = swap_model(&path, backend, window_len, &model, &templates);
+            match &result {
+                Ok(status) => events.log(EventKind::ModelSwap, status.clone()),
+                Err(e) => error!("Model swap to {:?} refused: {}", path, e),
+            }
+            tx.send(result).ok();
+        });
+        self.loading = Some(rx);
+    }
+}
+
+// Load and check `path`, then make it the live model. If the loaded templates don't fit the new model its
+// default template replaces them, otherwise the swap is refused and the old model stays in place
+fn swap_model(path: &Path, backend: ModelBackend, window_len: usize, model: &SharedModel, templates: &Arc<RwLock<Vec<Template>>>) -> Result<String, String> {
+    let new_model = Model::load(path, backend).map_err(|e| e.to_string())?;
+    let mut status = format!("Swapped to {}", path.display());
+
+    let mut templates = templates.write().unwrap();
+    if let Err(e) = new_model.check_session(window_len, &templates) {
+        let default = new_model.meta.default_template.as_ref().ok_or_else(|| e.to_string())?;
+        let template = Template::load(default).map_err(|e| e.to_string())?;
+        new_model.check_session(window_len, std::slice::from_ref(&template)).map_err(|e| e.to_string())?;
+        warn!("Loaded templates don't fit {:?}, replacing them with its default template '{}'", path, template.name);
+        status = format!("{} with template '{}'", status, template.name);
+        *templates = vec![template];
+    }
+    *model.write().unwrap() = Some(Arc::new(new_model));
+    info!("{}", status);
+    Ok(status)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
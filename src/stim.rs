@@ -1,6 +1,178 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use csv::Writer;
+use serialport::SerialPort;
+use tracing::{error, info, warn};
 
-pub fn qualifies_for_stimulation(v0: &[f64], v1: &[f64], averages: (f64, f64), stddevs: (f64, f64), percentage: f64) -> bool {
-    let sigma_level = 1.0;
+use crate::config::StimulationConfig;
+use crate::util::unix_timestamp_ms;
+
+// Serial command set spoken to the stimulator (see README.md). Commands and replies are ASCII lines ending in
+// '\n', all times are integer microseconds:
+//   CFG <pulse_width> <period> <pulses_per_train> <trains> <inter_train_interval>   -> OK | ERR <reason>
+//   STIM                                                                             -> TRAIN <n> ... DONE
+//   STOP                                                                             -> STOPPED
+// The device reports TRAIN <n> (1-based) as each train starts and DONE after the last one. STOP aborts a
+// running protocol straight away
+#[derive(Debug)]
+pub enum StimError {
+    Serial(serialport::Error),
+    Io(io::Error),
+    Rejected(String),
+    NoReply(Duration),
+}
+
+impl fmt::Display for StimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StimError::Serial(e) => write!(f, "could not open stimulator: {}", e),
+            StimError::Io(e) => write!(f, "stimulator i/o failed: {}", e),
+            StimError::Rejected(reason) => write!(f, "stimulator rejected the protocol: {}", reason),
+            StimError::NoReply(timeout) => write!(f, "stimulator did not answer within {:?}", timeout),
+        }
+    }
+}
+
+impl std::error::Error for StimError {}
+
+impl From<serialport::Error> for StimError {
+    fn from(e: serialport::Error) -> Self {
+        StimError::Serial(e)
+    }
+}
+
+impl From<io::Error> for StimError {
+    fn from(e: io::Error) -> Self {
+        StimError::Io(e)
+    }
+}
+
+pub fn protocol_command(protocol: &StimulationConfig) -> String {
+    let us = |ms: f64| (ms * 1000.0).round() as u64;
+    format!("CFG {} {} {} {} {}\n",
+        us(protocol.pulse_width_ms),
+        us(protocol.period_ms()),
+        protocol.pulses_per_train(),
+        protocol.trains,
+        us(protocol.inter_train_interval_ms))
+}
+
+// The stimulator on the output port. Opening it uploads the protocol, after that every `deliver` runs the whole
+// protocol once. A background thread reads the device's train reports and logs them to stim<N>.csv as
+// host_unix_ms, stimulation number, train number, pulses, pulse_width_ms, frequency_hz
+pub struct StimDevice {
+    port: Box<dyn SerialPort>,
+}
+
+impl StimDevice {
+    pub fn open(path: &str, baud_rate: u32, protocol: &StimulationConfig, log: Writer<File>) -> Result<Self, StimError> {
+        let mut port = serialport::new(path, baud_rate)
+            .timeout(Duration::from_millis(10))
+            .open()?;
+        let mut reader = BufReader::new(port.try_clone()?);
+
+        let command = protocol_command(protocol);
+        port.write_all(command.as_bytes())?;
+        let timeout = Duration::from_millis(protocol.ack_timeout_ms);
+        let started = Instant::now();
+        let mut line = String::new();
+        loop {
+            if read_line(&mut reader, &mut line)? {
+                match line.trim() {
+                    "OK" => break,
+                    reply if reply.starts_with("ERR") => return Err(StimError::Rejected(reply[3..].trim().to_string())),
+                    reply => warn!("Ignoring stimulator reply '{}' while configuring", reply),
+                }
+                line.clear();
+            }
+            if started.elapsed() > timeout {
+                return Err(StimError::NoReply(timeout));
+            }
+        }
+        info!("Stimulator on {} accepted protocol {}", path, command.trim());
+
+        let protocol = protocol.clone();
+        thread::spawn(move || log_trains(reader, log, protocol));
+        Ok(Self { port })
+    }
+
+    // Start one run of the configured protocol
+    pub fn deliver(&mut self) -> Result<(), StimError> {
+        self.port.write_all(b"STIM\n")?;
+        Ok(())
+    }
+
+    // A second handle on the port for the E-stop, so it doesn't have to wait on the analysis thread
+    pub fn stop_handle(&self) -> Result<StimStop, StimError> {
+        Ok(StimStop { port: self.port.try_clone()? })
+    }
+}
+
+pub struct StimStop {
+    port: Box<dyn SerialPort>,
+}
+
+impl StimStop {
+    pub fn stop(&mut self) -> Result<(), StimError> {
+        self.port.write_all(b"STOP\n")?;
+        Ok(())
+    }
+}
+
+// Reads into `line` until it holds a full line. Returns false if the port timed out first, keeping what was read
+fn read_line(reader: &mut BufReader<Box<dyn SerialPort>>, line: &mut String) -> io::Result<bool> {
+    match reader.read_line(line) {
+        Ok(_) => Ok(line.ends_with('\n')),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn log_trains(mut reader: BufReader<Box<dyn SerialPort>>, mut log: Writer<File>, protocol: StimulationConfig) {
+    let mut stimulation = 0u64;
+    let mut line = String::new();
+    loop {
+        match read_line(&mut reader, &mut line) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => {
+                error!("Lost the stimulator: {}", e);
+                return;
+            }
+        }
+
+        let reply = line.trim().to_string();
+        line.clear();
+        let mut words = reply.split_whitespace();
+        match (words.next(), words.next().and_then(|n| n.parse::<u32>().ok())) {
+            (Some("TRAIN"), Some(train)) => {
+                if train == 1 {
+                    stimulation += 1;
+                }
+                log.write_record(&[
+                    unix_timestamp_ms().to_string(),
+                    stimulation.to_string(),
+                    train.to_string(),
+                    protocol.pulses_per_train().to_string(),
+                    protocol.pulse_width_ms.to_string(),
+                    protocol.frequency_hz.to_string(),
+                ]).expect("Could not write to CSV output");
+                log.flush().ok();
+                info!("Stimulator delivered train {} of {} (stimulation {})", train, protocol.trains, stimulation);
+            }
+            (Some("DONE"), _) => {}
+            (Some("STOPPED"), _) => warn!("Stimulator aborted stimulation {}", stimulation),
+            _ => warn!("Unexpected stimulator reply '{}'", reply),
+        }
+    }
+}
+
+// Whether more than `percentage` of both channels' samples sit below their average + `sigma_level` standard
+// deviations. Used by the `channel_percentile` stimulation rule
+pub fn qualifies_for_stimulation(v0: &[f64], v1: &[f64], averages: (f64, f64), stddevs: (f64, f64), sigma_level: f64, percentage: f64) -> bool {
     let target0 = averages.0 + (sigma_level * stddevs.0);
     let target1 = averages.1 + (sigma_level * stddevs.1);
 
@@ -22,6 +194,32 @@ pub fn qualifies_for_stimulation(v0: &[f64], v1: &[f64], averages: (f64, f64), s
 
     let percentage_to_target = ((count.0 as f64 / total.0 as f64), (count.1 as f64 / total.1 as f64));
     //println!("{:?}", percentage_to_target);
-    percentage_to_target.0 > percentage && percentage_to_target.0 > percentage
+    percentage_to_target.0 > percentage && percentage_to_target.1 > percentage
+
+}
 
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protocol_command_is_in_microseconds() {
+        let protocol = StimulationConfig {
+            pulse_width_ms: 5.0,
+            frequency_hz: 20.0,
+            train_duration_ms: 1000.0,
+            trains: 3,
+            inter_train_interval_ms: 2500.0,
+            ack_timeout_ms: 1000,
+        };
+        assert_eq!(protocol_command(&protocol), "CFG 5000 50000 20 3 2500000\n");
+    }
+
+    #[test]
+    fn percentile_checks_both_channels() {
+        let low = [0.0, 0.0, 0.0, 0.0];
+        let high = [9.0, 9.0, 9.0, 0.0];
+        assert!(qualifies_for_stimulation(&low, &low, (1.0, 1.0), (1.0, 1.0), 1.0, 0.5));
+        assert!(!qualifies_for_stimulation(&low, &high, (1.0, 1.0), (1.0, 1.0), 1.0, 0.5));
+    }
+}
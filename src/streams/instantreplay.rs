@@ -1,128 +1,99 @@
-use std::sync::{Mutex, Arc, RwLock};
-use std::sync::mpsc::Sender;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
 use spin_sleep::sleep;
-use tracing::{debug, error, info, warn};
-use csv::Writer;
-use std::thread;
-use std::time::{Instant, Duration};
-use serde::Deserialize;
-use crate::structs::RasaVariables;
+use tracing::{error, warn};
 
-use crate::threadedchannel::{BoundedSender, deque_channel};
-use crate::util::*;
+use crate::inputstream::{ActiveLowTtl, InputStream, Sample};
 
-use std::error::Error;
-use std::io::Read;
-use std::path::Path;
+// Plays back a recording made by Rasa (`time, unix_ms`, the inputs, then any derived channels) as fast as the
+// analysis can keep up with. Samples keep their recorded time, so windows, baselines and events follow the
+// recording's timebase like analysis::for_each_window does
+pub struct InstantReplay {
+    records: csv::StringRecordsIntoIter<File>,
+    inputs: usize,
+    // Input column of the recorded TTL line, read like PhotometryStream reads the rig's
+    ttl_column: Option<usize>,
+    ttl: ActiveLowTtl,
+    // Data rows read so far, for the warnings
+    row: usize,
+}
 
-pub fn start_instant_replay(file: String, tx: Sender<[(f64, f64); 4]>, tx_deque0: &BoundedSender, tx_deque1: &BoundedSender, tx_time: &BoundedSender, mut writer: Writer<File>, vars: &Arc<RwLock<RasaVariables>>) {
-    let vec_mutex = Mutex::new(Vec::new());
-    let path = Path::new(&file);
-    let file = File::open(&path);
-    let mut sec_start = Instant::now();
-    let start = Instant::now();
-    let mut ix: usize = 0;
-    let mut old_average = (0f64, 0f64);
-    let mut old_std = (0f64, 0f64);
+impl InstantReplay {
+    pub fn open(file: &String, inputs: usize, ttl_column: Option<usize>) -> Self {
+        let path = Path::new(file);
+        let file = File::open(&path).expect("Could not open replay file");
 
-    // Create a CSV reader.
-    let mut reader = csv::Reader::from_reader(file.unwrap());
+        // Create a CSV reader. Rows of any length are read, short ones are skipped below
+        let reader = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+        Self {
+            records: reader.into_records(),
+            inputs,
+            ttl_column,
+            ttl: ActiveLowTtl::new(),
+            row: 0,
+        }
+    }
+}
 
-    for result in reader.records() {
-        let skip = vars.read().unwrap().skip;
-        // The iterator yields Result<StringRecord, Error>, so we check the error here.
-        let record = result.unwrap();
+impl InputStream for InstantReplay {
+    fn name(&self) -> &'static str {
+        "Instant Replay"
+    }
 
-        // Ensure there are exactly 5 columns
-        if record.len() != 5 {
-            error!("Expected 5 columns");
-        }
+    // Malformed rows are skipped with a warning, only the end of the file or a read error ends the replay
+    fn next_sample(&mut self) -> Option<Sample> {
+        let (time, inputs) = loop {
+            self.row += 1;
+            let record = match self.records.next()? {
+                Ok(record) => record,
+                Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => {
+                    error!("Could not read the replay file: {}", e);
+                    return None;
+                }
+                Err(e) => {
+                    warn!("Skipping replay row {}: {}", self.row, e);
+                    continue;
+                }
+            };
 
-        // Parse the columns into f64 values.
-        let values: Result<Vec<f64>, _> = record.iter().map(|s| s.parse()).collect();
+            // Only the inputs are replayed, the derived channels are derived again
+            if record.len() < 2 + self.inputs {
+                warn!("Skipping replay row {}: {} columns, expected at least {}", self.row, record.len(), 2 + self.inputs);
+                continue;
+            }
+            let mut columns = record.iter().take(2 + self.inputs).map(|s| s.trim().parse::<f64>());
+            let time = columns.next().unwrap_or(Ok(f64::NAN));
+            match (time, columns.skip(1).collect::<Result<Vec<f64>, _>>()) {
+                (Ok(time), _) if !time.is_finite() => warn!("Skipping replay row {}: time is {}", self.row, time),
+                (Ok(time), Ok(inputs)) => break (time, inputs),
+                (Err(e), _) | (_, Err(e)) => warn!("Skipping replay row {}: {}", self.row, e),
+            }
+        };
 
-        // If parsing was successful, print the values. Otherwise, return the error.
-        let  (mut y0, mut y1) = (0.0, 0.0);
-        match values {
-            Ok(values) => {
-                y0 = values[2];
-                y1 = values[3];
-            },
-            Err(err) => error!("Could not read line!"),
-        }
-        let elapsed: f64 = (start.elapsed().as_millis() as f64) / 1000.0;
-        let num = [
-            (elapsed, y0 as f64),
-            (elapsed, y1 as f64),
-            (elapsed, 0.0),
-            (elapsed, 0.0),
-        ];
-        tx.send(num).unwrap();
-        if ix % skip == 0 {
-            tx_deque0.send(y0 as f32);
-            tx_deque1.send(y1 as f32);
-            tx_time.send(elapsed as f32);
-        }
+        // A missing TTL column reads as low
+        let ttl = self.ttl.push(self.ttl_column.and_then(|ix| inputs.get(ix)).copied().unwrap_or(0.0));
 
-        let mut vec = vec_mutex.lock().unwrap();
-        vec.push(vec![y0, y1]);
-        if sec_start.elapsed() > Duration::from_secs(1) {
-            sec_start = Instant::now();
-            let v0 = vec.clone().into_iter().filter_map(|v| v.into_iter().nth(0)).collect::<Vec<_>>();
-            let v1 = vec.clone().into_iter().filter_map(|v| v.into_iter().nth(1)).collect::<Vec<_>>();
-            old_average = average_vec(&vec);
-            old_std = std_dev(&v0,&v1);
-            vec.clear();
-        }
-        ix += 1;
         sleep(Duration::from_micros(100));
+        Some(Sample::new(time, inputs, ttl))
     }
-    /*
-    let mut rdr = csv::Reader::from_reader(std::io::stdin());
-    match rdr {
-        Ok(mut r) => {
-            let mut ix: usize = 0;
-            for result in r.deserialize() {
-                // The iterator yields Result<StringRecord, Error>, so we check the
-                // error here
-                let record: Record = result.unwrap();
-                match (
-                    record.col3,
-                    record.col4) {
-                    (Some(y0), Some(y1)) => {
-                        let elapsed = (start.elapsed().as_millis() as f64) / 1000.0;
-                        //println!("{} {} {}", elapsed, y0, y1);
-                        let num = [
-                            (elapsed, y0 as f64),
-                            (elapsed, y1 as f64),
-                            (0.0, 0.0),
-                            (0.0, 0.0),
-                        ];
-                        let skip = 4;
-                        tx.send(num).unwrap();
-                        if ix % skip == 0 {
-                            tx_deque0.send(y0 as f32);
-                            tx_deque1.send(y1 as f32);
-                            tx_time.send(elapsed as f32);
-                        }
-                        let mut vec = vec_mutex.lock().unwrap();
-                        vec.push(vec![y0, y1]);
-                        thread::sleep(Duration::from_micros(1));
-                    }
-                    _ => {
-                        error!("Failed to read line!")
-                    }
-                }
-                ix += 1;
-            }
-        }
-        Err(e) => {
-            error!("Failed to open CSV file with error {}", e);
-        }
+}
 
-    }
+#[cfg(test)]
+mod test {
+    use super::*;
 
-     */
-}
\ No newline at end of file
+    #[test]
+    fn skips_malformed_rows() {
+        let path = std::env::temp_dir().join(format!("rasa_replay_{}.csv", std::process::id()));
+        std::fs::write(&path, "time,unix_ms,signal,isosbestic,ttl\n0.0,0,1.0,2.0,0\n0.01,0,oops,2.0,0\n0.02,0\n0.03,0,3.0,4.0,0\n").unwrap();
+        let mut replay = InstantReplay::open(&path.to_string_lossy().to_string(), 3, Some(2));
+        let first = replay.next_sample().unwrap();
+        assert_eq!((first.time, first.values, first.ttl), (0.0, vec![1.0, 2.0, 0.0], false));
+        // The second low reading asserts the TTL
+        let second = replay.next_sample().unwrap();
+        assert_eq!((second.time, second.values, second.ttl), (0.03, vec![3.0, 4.0, 0.0], true));
+        assert!(replay.next_sample().is_none());
+        std::fs::remove_file(path).ok();
+    }
+}
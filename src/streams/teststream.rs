@@ -1,26 +1,37 @@
 use std::time::{Instant, Duration};
 use std::thread;
-use std::sync::mpsc::Sender;
 
-pub fn start_test_stream(tx: Sender<[(f64, f64); 4]>) {
-    let mut ix = 1i32;
-    let start = Instant::now();
-    loop {
-        let y: f64 = match ix % 100 {
+use crate::inputstream::{InputStream, Sample};
+
+// Sin + spike data
+pub struct TestStream {
+    ix: i32,
+    start: Instant,
+}
+
+impl TestStream {
+    pub fn new() -> Self {
+        Self {
+            ix: 1,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl InputStream for TestStream {
+    fn name(&self) -> &'static str {
+        "Test"
+    }
+
+    fn next_sample(&mut self) -> Option<Sample> {
+        let y: f64 = match self.ix % 100 {
             0 => 2.0,
-            _ => (ix as f64).sin()
+            _ => (self.ix as f64).sin()
         };
-        let elapsed: f64 = (start.elapsed().as_millis() as f64) / 1000.0;
-        let num = [
-            (elapsed, y),
-            (elapsed, y * y),
-            (elapsed, 0.0),
-            (elapsed, 0.0)
-        ];
-        //println!("{:?}", num);
-        tx.send(num).unwrap();
+        let elapsed: f64 = (self.start.elapsed().as_millis() as f64) / 1000.0;
 
         thread::sleep(Duration::from_micros(10));
-        ix += 1;
+        self.ix += 1;
+        Some(Sample::new(elapsed, vec![y, y * y], false))
     }
 }
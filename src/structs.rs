@@ -3,8 +3,25 @@ pub struct RasaVariables {
     pub show_box: bool,
     // Control the number of seconds the graphs look backward
     pub look_behind: usize,
-    // Temporary, number of points to skip for the LSTM optimization purposes (expands the size of the box)
-    // TODO: Replace with autosizing the box based on float time
-    pub skip: usize,
-    pub channels: usize,
+    // Seconds of data in the model window (the box on the plot), resampled to the model's length
+    pub window_secs: f64,
+    // Whether ΔF/F is derived, see processing.rs
+    pub dff: bool,
+    // Whether filters or detrending change channels 0 and 1, and if so whether the plot shows the raw values
+    pub processed: bool,
+    pub show_raw: bool,
+    // Group delay of each channel's filters at processing.delay_at_hz
+    pub filter_delay_ms: Option<(f64, f64)>,
+    // The rate the filters are designed for and, with filters, the rate last measured on the input
+    pub sample_rate_hz: f64,
+    pub input_rate_hz: Option<f64>,
+    // The primary detector's threshold and the stimulation cooldown, editable from the sidebar
+    pub threshold: f64,
+    pub cooldown_secs: u64,
+    // The shortest cooldown that doesn't cut into a running stimulation protocol
+    pub min_cooldown_secs: u64,
+    // Set by the analysis thread in adaptive mode: the z-score currently needed to fire and the score that
+    // corresponds to
+    pub sigma: Option<f64>,
+    pub adaptive_threshold: Option<f64>,
 }
\ No newline at end of file
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A named embedding of an event we want to detect. On disk this is either a JSON file
+// `{"name": "peak32", "vector": [...]}` or a 1-D float `.npy` array, in which case the file stem is the name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
+    pub name: String,
+    pub vector: Vec<f64>,
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(PathBuf, std::io::Error),
+    Json(PathBuf, serde_json::Error),
+    Npy(PathBuf, String),
+    UnknownFormat(PathBuf),
+    Empty(PathBuf),
+    DimensionMismatch { name: String, expected: usize, got: usize },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Io(path, e) => write!(f, "could not access template {:?}: {}", path, e),
+            TemplateError::Json(path, e) => write!(f, "could not parse template {:?}: {}", path, e),
+            TemplateError::Npy(path, e) => write!(f, "could not read npy template {:?}: {}", path, e),
+            TemplateError::UnknownFormat(path) => write!(f, "template {:?} is neither .json nor .npy", path),
+            TemplateError::Empty(path) => write!(f, "template {:?} has no values", path),
+            TemplateError::DimensionMismatch { name, expected, got } =>
+                write!(f, "template '{}' has {} values but the model outputs {}", name, got, expected),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Template {
+    pub fn load(path: &Path) -> Result<Self, TemplateError> {
+        let template = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => {
+                let text = fs::read_to_string(path).map_err(|e| TemplateError::Io(path.to_path_buf(), e))?;
+                serde_json::from_str::<Template>(&text).map_err(|e| TemplateError::Json(path.to_path_buf(), e))?
+            }
+            Some("npy") => {
+                let bytes = fs::read(path).map_err(|e| TemplateError::Io(path.to_path_buf(), e))?;
+                Template {
+                    name: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+                    vector: read_npy(&bytes).map_err(|e| TemplateError::Npy(path.to_path_buf(), e))?,
+                }
+            }
+            _ => return Err(TemplateError::UnknownFormat(path.to_path_buf())),
+        };
+
+        if template.vector.is_empty() {
+            return Err(TemplateError::Empty(path.to_path_buf()));
+        }
+        Ok(template)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TemplateError> {
+        let text = serde_json::to_string_pretty(self).map_err(|e| TemplateError::Json(path.to_path_buf(), e))?;
+        fs::write(path, text).map_err(|e| TemplateError::Io(path.to_path_buf(), e))
+    }
+
+    pub fn distance(&self, embedding: &[f64]) -> Result<f64, TemplateError> {
+        if embedding.len() != self.vector.len() {
+            return Err(TemplateError::DimensionMismatch {
+                name: self.name.clone(),
+                expected: embedding.len(),
+                got: self.vector.len(),
+            });
+        }
+        Ok(self.vector.iter().zip(embedding).map(|(a, b)| (a - b).powi(2)).sum::<f64>().sqrt())
+    }
+}
+
+pub fn load_templates(paths: &[PathBuf]) -> Result<Vec<Template>, TemplateError> {
+    paths.iter().map(|p| Template::load(p)).collect()
+}
+
+// The template closest to the embedding, as (index into `templates`, distance)
+pub fn nearest(embedding: &[f64], templates: &[Template]) -> Result<Option<(usize, f64)>, TemplateError> {
+    let mut best: Option<(usize, f64)> = None;
+    for (ix, template) in templates.iter().enumerate() {
+        let distance = template.distance(embedding)?;
+        if best.map_or(true, |(_, d)| distance < d) {
+            best = Some((ix, distance));
+        }
+    }
+    Ok(best)
+}
+
+// Minimal reader for what numpy.save writes for a 1-D (or 1xN) little-endian float32/float64 array. Anything with
+// more than one row would otherwise be flattened into one long template, so other shapes are refused
+fn read_npy(bytes: &[u8]) -> Result<Vec<f64>, String> {
+    if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+        return Err(String::from("missing npy magic"));
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12),
+        v => return Err(format!("unsupported npy version {}", v)),
+    };
+    let data_start = header_start + header_len;
+    if bytes.len() < data_start {
+        return Err(String::from("truncated header"));
+    }
+    let header = String::from_utf8_lossy(&bytes[header_start..data_start]);
+    if header.contains("'fortran_order': True") {
+        return Err(String::from("fortran ordered arrays are not supported"));
+    }
+
+    let len = match npy_shape(&header)?.as_slice() {
+        [len] | [1, len] => *len,
+        shape => return Err(format!("expected a 1-D array, got shape {:?}", shape)),
+    };
+
+    let data = &bytes[data_start..];
+    let values: Vec<f64> = if header.contains("'<f8'") {
+        data.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap())).collect()
+    } else if header.contains("'<f4'") {
+        data.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64).collect()
+    } else {
+        return Err(format!("unsupported dtype in header {}", header.trim()));
+    };
+    if values.len() != len {
+        return Err(format!("header says {} values but the file has {}", len, values.len()));
+    }
+    Ok(values)
+}
+
+// The dimensions in the header's `'shape': (...)`
+fn npy_shape(header: &str) -> Result<Vec<usize>, String> {
+    let missing = || format!("no shape in header {}", header.trim());
+    let after = &header[header.find("'shape':").ok_or_else(missing)? + "'shape':".len()..];
+    let open = after.find('(').ok_or_else(missing)?;
+    let close = after.find(')').ok_or_else(missing)?;
+    after[open + 1..close]
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| format!("bad shape in header {}", header.trim())))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // What numpy.save writes: magic, version 1.0, header padded to 64 bytes, data
+    fn npy(descr: &str, shape: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    fn write(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rasa_template_{}_{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_json_and_npy() {
+        let json = write("peak.json", br#"{"name": "peak", "vector": [1.0, 2.5]}"#);
+        assert_eq!(Template::load(&json).unwrap().vector, vec![1.0, 2.5]);
+
+        let f8: Vec<u8> = [1.0f64, -2.0, 0.5].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let f4: Vec<u8> = [1.0f32, -2.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let rise = write("rise.npy", &npy("<f8", "(3,)", &f8));
+        let template = Template::load(&rise).unwrap();
+        assert_eq!((template.name.ends_with("rise"), template.vector), (true, vec![1.0, -2.0, 0.5]));
+        assert_eq!(read_npy(&npy("<f4", "(1, 2)", &f4)).unwrap(), vec![1.0, -2.0]);
+
+        assert!(read_npy(&npy("<i8", "(3,)", &f8)).unwrap_err().contains("dtype"));
+        let two_d: Vec<u8> = [0.0f64; 4].iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert!(read_npy(&npy("<f8", "(2, 2)", &two_d)).unwrap_err().contains("1-D"));
+        assert!(read_npy(&npy("<f8", "(4,)", &f8)).is_err());
+        for path in [json, rise] {
+            fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn nearest_template_wins() {
+        let templates = vec![
+            Template { name: String::from("a"), vector: vec![0.0, 0.0] },
+            Template { name: String::from("b"), vector: vec![3.0, 4.0] },
+        ];
+        assert_eq!(nearest(&[2.0, 4.0], &templates).unwrap(), Some((1, 1.0)));
+        assert_eq!(nearest(&[2.0, 4.0], &[]).unwrap(), None);
+        assert!(nearest(&[1.0], &templates).is_err());
+    }
+}
@@ -1,6 +1,5 @@
-const STDDEV: f64 = 50.0;
-
 use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn average_vec(vec: &Vec<Vec<f64>>) -> (f64, f64) {
     let (sum0, count0) = vec.iter()
@@ -16,9 +15,10 @@ pub fn average_vec(vec: &Vec<Vec<f64>>) -> (f64, f64) {
 }
 
 
-pub fn normalize_array(lower_: &Vec<f64>, high_: &Vec<f64>) -> (Vec<f64>, Vec<f64>) {
-    let mut v0 = lower_.clone();
-    let mut v1 = high_.clone();
+// `stddev` and `offsets` are what the model was trained with, see the model's metadata file
+pub fn normalize_array(lower_: &[f64], high_: &[f64], stddev: f64, offsets: (f64, f64)) -> (Vec<f64>, Vec<f64>) {
+    let mut v0 = lower_.to_vec();
+    let mut v1 = high_.to_vec();
 
     // Get each min and max value from the arrays in order to normalize them
     // There has got to be an easier way to do this, but this is the best that I can find
@@ -31,16 +31,16 @@ pub fn normalize_array(lower_: &Vec<f64>, high_: &Vec<f64>) -> (Vec<f64>, Vec<f6
 
     // Actually a mistake, but I generated the model by subtracting the min, so obviously I have to do the same thing
     let mut normalized_arr: (Vec<f64>, Vec<f64>) = (
-        v0.iter().map(|&x| (x - min_val) / STDDEV).collect(),
-        v1.iter().map(|&x| (x - min_val) / STDDEV).collect(),
+        v0.iter().map(|&x| (x - min_val) / stddev).collect(),
+        v1.iter().map(|&x| (x - min_val) / stddev).collect(),
     );
 
     let avg0: f64 = normalized_arr.0.iter().sum::<f64>() / normalized_arr.0.len() as f64;
     let avg1: f64 = normalized_arr.1.iter().sum::<f64>() / normalized_arr.1.len() as f64;
 
-    normalized_arr.0.iter_mut().for_each(|x| *x -= avg0);
-    // Offset by 3 for the RNN to distinguish the channels. Could have passed channels 2 at a time. Didn't.
-    normalized_arr.1.iter_mut().for_each(|x| *x -= (avg1 + 3.0));
+    normalized_arr.0.iter_mut().for_each(|x| *x -= avg0 + offsets.0);
+    // Offset (3 for the original models) for the RNN to distinguish the channels. Could have passed channels 2 at a time. Didn't.
+    normalized_arr.1.iter_mut().for_each(|x| *x -= avg1 + offsets.1);
 
     normalized_arr
 }
@@ -109,4 +109,35 @@ pub fn average_vec_deque(data: &VecDeque<f64>) -> Option<f64> {
     } else {
         None
     }
-}
\ No newline at end of file
+}
+
+// Gaussian elimination with partial pivoting on an augmented n×(n+1) matrix. None when it is singular or has
+// a NaN or inf in it
+pub fn solve_linear(mut m: Vec<Vec<f64>>) -> Option<Vec<f64>> {
+    if m.iter().flatten().any(|v| !v.is_finite()) {
+        return None;
+    }
+    let n = m.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|a, b| m[*a][col].abs().total_cmp(&m[*b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        for r in 0..n {
+            if r != col {
+                let factor = m[r][col] / m[col][col];
+                for c in col..=n {
+                    m[r][c] -= factor * m[col][c];
+                }
+            }
+        }
+    }
+    Some((0..n).map(|i| m[i][n] / m[i][i]).collect())
+}
+
+pub fn unix_timestamp_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}